@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD COLUMN auth_key BLOB;
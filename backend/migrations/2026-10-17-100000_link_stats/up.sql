@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE link_stats (
+    device_id INTEGER PRIMARY KEY NOT NULL,
+    boot_id BIGINT NOT NULL,
+    last_sequence BIGINT NOT NULL,
+    seen_window BIGINT NOT NULL,
+    received BIGINT NOT NULL,
+    missing BIGINT NOT NULL,
+    duplicates BIGINT NOT NULL
+);
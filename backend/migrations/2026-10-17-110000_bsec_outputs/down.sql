@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE measurements DROP COLUMN iaq;
+ALTER TABLE measurements DROP COLUMN iaq_accuracy;
+ALTER TABLE measurements DROP COLUMN co2;
+ALTER TABLE measurements DROP COLUMN voc;
+ALTER TABLE measurements DROP COLUMN raw_gas;
+ALTER TABLE measurements DROP COLUMN stabilization_status;
+ALTER TABLE measurements DROP COLUMN run_in_status;
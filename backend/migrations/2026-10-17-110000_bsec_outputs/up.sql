@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE measurements ADD COLUMN iaq REAL;
+ALTER TABLE measurements ADD COLUMN iaq_accuracy INTEGER;
+ALTER TABLE measurements ADD COLUMN co2 REAL;
+ALTER TABLE measurements ADD COLUMN voc REAL;
+ALTER TABLE measurements ADD COLUMN raw_gas REAL;
+ALTER TABLE measurements ADD COLUMN stabilization_status BOOLEAN;
+ALTER TABLE measurements ADD COLUMN run_in_status BOOLEAN;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL,
+    kind TEXT NOT NULL,
+    event TEXT NOT NULL -- json
+);
+
+CREATE INDEX events_device_timestamp ON events (device_id, timestamp);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE device_diagnostics (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL,
+    rssi INTEGER,
+    connect_attempts INTEGER NOT NULL,
+    connect_duration INTEGER NOT NULL,
+    free_heap BIGINT NOT NULL,
+    reset_reason INTEGER NOT NULL
+);
+
+CREATE INDEX device_diagnostics_device_timestamp ON device_diagnostics (device_id, timestamp);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE commands (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    command TEXT NOT NULL, -- json
+    status INTEGER NOT NULL, -- see `req::CommandStatus`
+    attempts INTEGER NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE INDEX commands_device_status ON commands (device_id, status);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE journal (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    received_at BIGINT NOT NULL, -- ms since epoch
+    source TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    data BLOB NOT NULL -- datagram as received
+);
+
+CREATE INDEX journal_received_at ON journal (received_at);
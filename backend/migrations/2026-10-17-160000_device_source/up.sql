@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD COLUMN source TEXT NOT NULL DEFAULT 'udp';
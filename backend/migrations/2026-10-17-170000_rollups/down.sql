@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE measurements_daily;
+DROP TABLE measurements_hourly;
@@ -0,0 +1,67 @@
+-- Your SQL goes here
+-- count, min, max and sum of every field per device and hour or day (UTC)
+CREATE TABLE measurements_hourly (
+    device_id INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL, -- start of the hour, ms since epoch
+    field TEXT NOT NULL, -- column of measurements
+    count INTEGER NOT NULL,
+    min DOUBLE NOT NULL,
+    max DOUBLE NOT NULL,
+    sum DOUBLE NOT NULL,
+    PRIMARY KEY (device_id, timestamp, field)
+);
+
+CREATE TABLE measurements_daily (
+    device_id INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL, -- start of the day, ms since epoch
+    field TEXT NOT NULL, -- column of measurements
+    count INTEGER NOT NULL,
+    min DOUBLE NOT NULL,
+    max DOUBLE NOT NULL,
+    sum DOUBLE NOT NULL,
+    PRIMARY KEY (device_id, timestamp, field)
+);
+
+INSERT INTO measurements_hourly (device_id, timestamp, field, count, min, max, sum)
+SELECT device_id, timestamp / 3600000 * 3600000, 'temperature', COUNT(temperature), MIN(temperature), MAX(temperature), TOTAL(temperature)
+FROM measurements WHERE temperature IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'humidity', COUNT(humidity), MIN(humidity), MAX(humidity), TOTAL(humidity)
+FROM measurements WHERE humidity IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'pressure', COUNT(pressure), MIN(pressure), MAX(pressure), TOTAL(pressure)
+FROM measurements WHERE pressure IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'air_quality', COUNT(air_quality), MIN(air_quality), MAX(air_quality), TOTAL(air_quality)
+FROM measurements WHERE air_quality IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'bat_v', COUNT(bat_v), MIN(bat_v), MAX(bat_v), TOTAL(bat_v)
+FROM measurements WHERE bat_v IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'bat_cap', COUNT(bat_cap), MIN(bat_cap), MAX(bat_cap), TOTAL(bat_cap)
+FROM measurements WHERE bat_cap IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'iaq', COUNT(iaq), MIN(iaq), MAX(iaq), TOTAL(iaq)
+FROM measurements WHERE iaq IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'iaq_accuracy', COUNT(iaq_accuracy), MIN(iaq_accuracy), MAX(iaq_accuracy), TOTAL(iaq_accuracy)
+FROM measurements WHERE iaq_accuracy IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'co2', COUNT(co2), MIN(co2), MAX(co2), TOTAL(co2)
+FROM measurements WHERE co2 IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'voc', COUNT(voc), MIN(voc), MAX(voc), TOTAL(voc)
+FROM measurements WHERE voc IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'raw_gas', COUNT(raw_gas), MIN(raw_gas), MAX(raw_gas), TOTAL(raw_gas)
+FROM measurements WHERE raw_gas IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'stabilization_status', COUNT(stabilization_status), MIN(stabilization_status), MAX(stabilization_status), TOTAL(stabilization_status)
+FROM measurements WHERE stabilization_status IS NOT NULL GROUP BY device_id, timestamp / 3600000
+UNION ALL
+SELECT device_id, timestamp / 3600000 * 3600000, 'run_in_status', COUNT(run_in_status), MIN(run_in_status), MAX(run_in_status), TOTAL(run_in_status)
+FROM measurements WHERE run_in_status IS NOT NULL GROUP BY device_id, timestamp / 3600000;
+
+INSERT INTO measurements_daily (device_id, timestamp, field, count, min, max, sum)
+SELECT device_id, timestamp / 86400000 * 86400000, field, SUM(count), MIN(min), MAX(max), SUM(sum)
+FROM measurements_hourly GROUP BY device_id, timestamp / 86400000, field;
@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE measurements_old (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    device_id INTEGER NOT NULL,
+    timestamp BIGINT NOT NULL,
+    temperature REAL,
+    humidity REAL,
+    pressure REAL,
+    air_quality REAL,
+    bat_v REAL,
+    bat_cap REAL
+);
+
+INSERT INTO measurements_old (device_id, timestamp, temperature, humidity, pressure, air_quality, bat_v, bat_cap)
+    SELECT device_id, timestamp, temperature, humidity, pressure, air_quality, bat_v, bat_cap FROM measurements;
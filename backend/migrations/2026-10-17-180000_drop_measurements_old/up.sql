@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- left over from the primary key migration, its rows were copied to measurements
+DROP TABLE measurements_old;
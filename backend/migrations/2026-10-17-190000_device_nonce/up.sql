@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- highest nonce of the encrypted packets, anything up to it is a replay
+ALTER TABLE devices ADD COLUMN last_nonce BLOB;
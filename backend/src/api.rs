@@ -1,22 +1,53 @@
-use std::{
-    io,
-    sync::{Arc, Mutex},
-};
+use std::{io, time::Instant};
 
 use actix_cors::Cors;
 use actix_web::{
+    delete,
+    dev::Service,
     get,
     http::header,
-    middleware, put,
+    middleware, post, put,
     web::{self, Data},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use rand::Rng;
+
+use crate::{
+    config,
+    db::{models, Pool},
+    ingest::{self, IngestError},
+    metrics, retention,
+};
+use common::{
+    packet::{auth, command::Command, Header, Packet, Payload, Protection, MAX_DATAGRAM_SIZE},
+    req::{Downsampling, IngestResponse, IngestStats, LinkStats, MeasurementInfo},
 };
 
-use crate::db::{models, Db};
-use common::req::MeasurementInfo;
+/// hex HMAC of a JSON packet, required if the device has a key
+const PACKET_TAG: &str = "X-Packet-Tag";
+
+/// Token of the admin routes, see `config::Http::admin_token`
+struct AdminToken(Option<String>);
+
+/// admin routes require `Authorization: Bearer <token>`, disabled if no token is configured
+fn is_admin(req: &HttpRequest, admin: &AdminToken) -> bool {
+    let Some(token) = &admin.0 else {
+        return false;
+    };
+
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| {
+            // compares the HMACs in constant time, not to leak the token by timing
+            let key = [0; auth::KEY_LEN];
+            auth::verify(&key, v.as_bytes(), &auth::tag(&key, token.as_bytes()))
+        })
+}
 
 #[get("/")]
-async fn hello(_db: web::Data<Arc<Mutex<Db>>>) -> impl Responder {
+async fn hello(_db: web::Data<Pool>) -> impl Responder {
     HttpResponse::Ok().body("backend")
 }
 
@@ -27,24 +58,31 @@ struct MeasurementsQueryByDate {
     to_date: Option<u64>,
     measurement_types: u32,
     limit: u32,
+    #[serde(default)]
+    downsampling: Downsampling,
 }
 
 #[get("/api/measurements/by_date")]
 async fn api_measurements_by_date(
     query: web::Query<MeasurementsQueryByDate>,
-    db: web::Data<Arc<Mutex<Db>>>,
+    db: web::Data<Pool>,
 ) -> io::Result<impl Responder> {
     dbg!(&query);
-    if let Ok(mut db) = db.lock() {
-        if let Ok(res) = db.measurements_by_date(
-            query.device_id,
-            query.from_date,
-            query.to_date,
-            query.measurement_types,
-            query.limit,
-        ) {
-            return Ok(web::Json(res));
-        }
+    let query = query.into_inner();
+    let res = db
+        .run(move |db| {
+            db.measurements_by_date(
+                query.device_id,
+                query.from_date,
+                query.to_date,
+                query.measurement_types,
+                query.limit,
+                query.downsampling,
+            )
+        })
+        .await;
+    if let Ok(res) = res {
+        return Ok(web::Json(res));
     }
     Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
 }
@@ -57,38 +95,33 @@ struct MeasurementsInfoQuery {
 #[get("/api/measurements/info")]
 async fn api_measurements_info(
     query: web::Query<MeasurementsInfoQuery>,
-    db: web::Data<Arc<Mutex<Db>>>,
+    db: web::Data<Pool>,
 ) -> io::Result<impl Responder> {
     dbg!(&query);
-    if let Ok(mut db) = db.lock() {
-        if let Ok(res) = db.measurement_info(query.device_id) {
-            return Ok(web::Json(MeasurementInfo {
-                device_id: query.device_id as i32,
-                from_timestamp: res.0,
-                to_timestamp: res.1,
-                count: res.2,
-            }));
-        }
+    let device_id = query.device_id;
+    if let Ok(res) = db.run(move |db| db.measurement_info(device_id)).await {
+        return Ok(web::Json(MeasurementInfo {
+            device_id: device_id as i32,
+            from_timestamp: res.0,
+            to_timestamp: res.1,
+            count: res.2,
+        }));
     }
     Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
 }
 
 #[get("/api/measurements/all")]
-async fn api_measurements_all(db: web::Data<Arc<Mutex<Db>>>) -> io::Result<impl Responder> {
-    if let Ok(mut db) = db.lock() {
-        if let Ok(res) = db.all_measurements() {
-            return Ok(web::Json(res));
-        }
+async fn api_measurements_all(db: web::Data<Pool>) -> io::Result<impl Responder> {
+    if let Ok(res) = db.run(|db| db.all_measurements()).await {
+        return Ok(web::Json(res));
     }
     Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
 }
 
 #[get("/api/devices")]
-async fn api_known_devices(db: web::Data<Arc<Mutex<Db>>>) -> io::Result<impl Responder> {
-    if let Ok(mut db) = db.lock() {
-        if let Ok(res) = db.devices() {
-            return Ok(web::Json(res));
-        }
+async fn api_known_devices(db: web::Data<Pool>) -> io::Result<impl Responder> {
+    if let Ok(res) = db.run(|db| db.devices()).await {
+        return Ok(web::Json(res));
     }
     Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
 }
@@ -102,19 +135,15 @@ struct SetDeviceNameParams {
 #[put("/api/device_name")]
 async fn api_set_device_name(
     query: web::Query<SetDeviceNameParams>,
-    db: web::Data<Arc<Mutex<Db>>>,
+    db: web::Data<Pool>,
 ) -> io::Result<impl Responder> {
     dbg!(&query);
-    if let Ok(mut db) = db.lock() {
-        if db
-            .update_device_name(&models::DeviceName {
-                device_id: query.device_id as i32,
-                name: query.name.clone(),
-            })
-            .is_ok()
-        {
-            return Ok(HttpResponse::Ok());
-        }
+    let name = models::DeviceName {
+        device_id: query.device_id as i32,
+        name: query.name.clone(),
+    };
+    if db.run(move |db| db.update_device_name(&name)).await.is_ok() {
+        return Ok(HttpResponse::Ok());
     }
     Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
 }
@@ -127,40 +156,391 @@ struct DeviceNameParams {
 #[get("/api/device_name")]
 async fn api_device_name(
     query: web::Query<DeviceNameParams>,
-    db: web::Data<Arc<Mutex<Db>>>,
+    db: web::Data<Pool>,
 ) -> io::Result<impl Responder> {
     dbg!(&query);
-    if let Ok(mut db) = db.lock() {
-        if let Ok(res) = db.device_name(query.device_id) {
-            return Ok(web::Json(res.name));
-        }
+    let device_id = query.device_id;
+    if let Ok(res) = db.run(move |db| db.device_name(device_id)).await {
+        return Ok(web::Json(res.name));
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "".to_string()))
+}
+
+#[get("/api/devices/{device_id}/link_stats")]
+async fn api_device_link_stats(
+    device_id: web::Path<u32>,
+    db: web::Data<Pool>,
+) -> io::Result<impl Responder> {
+    let device_id = *device_id;
+    if let Ok(Some(res)) = db.run(move |db| db.link_stats(device_id)).await {
+        return Ok(web::Json(LinkStats::from(res)));
     }
     Err(io::Error::new(io::ErrorKind::NotFound, "".to_string()))
 }
 
-pub async fn new_http_server(db: Arc<Mutex<Db>>) -> std::io::Result<()> {
+#[get("/api/ingest/stats")]
+async fn api_ingest_stats(ingest: web::Data<ingest::Ingest>) -> web::Json<IngestStats> {
+    web::Json(ingest.stats())
+}
+
+/// Prometheus metrics, the latest measurements are labeled with the device names
+#[get("/metrics")]
+async fn api_metrics(
+    db: web::Data<Pool>,
+    ingest: web::Data<ingest::Ingest>,
+) -> io::Result<impl Responder> {
+    let res = db
+        .run(|db| anyhow::Ok((db.latest_measurements()?, db.device_names()?)))
+        .await;
+    if let Ok((measurements, names)) = res {
+        if let Ok(text) = metrics::render(&measurements, &names, &ingest.stats()) {
+            return Ok(HttpResponse::Ok()
+                .content_type(metrics::CONTENT_TYPE)
+                .body(text));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
+}
+
+/// JSON body of `/api/ingest`, the header lacks the wire fields (magic, version and flags),
+/// the packet is encoded with the current protocol version
+#[derive(serde::Deserialize, Debug)]
+struct IngestPacket {
+    header: IngestHeader,
+    payload: Payload,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct IngestHeader {
+    device_id: u32,
+    #[serde(default)]
+    boot_id: u32,
+    #[serde(default)]
+    sequence: u32,
+    timestamp: u64,
+    #[serde(default)]
+    rel_timestamp: i64,
+}
+
+impl From<IngestPacket> for Packet {
+    fn from(packet: IngestPacket) -> Self {
+        let mut header = Header::new(packet.header.device_id, packet.header.timestamp);
+        header.boot_id = packet.header.boot_id;
+        header.sequence = packet.header.sequence;
+        header.rel_timestamp = packet.header.rel_timestamp;
+        Self {
+            header,
+            payload: packet.payload,
+        }
+    }
+}
+
+/// Ingests a packet of a device or relay that cannot reach the UDP listener, either the datagram
+/// as broadcast (postcard) or the `Packet` as JSON, both take the same path as the UDP packets
+#[post("/api/ingest")]
+async fn api_ingest(
+    req: HttpRequest,
+    body: web::Bytes,
+    db: web::Data<Pool>,
+    ingest: web::Data<ingest::Ingest>,
+) -> io::Result<HttpResponse> {
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+
+    let datagram = if is_json {
+        let packet: Packet = match serde_json::from_slice::<IngestPacket>(&body) {
+            Ok(packet) => packet.into(),
+            Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+        };
+
+        let device_id = packet.header.device_id;
+        let Ok(key) = db.run(move |db| db.device_key(device_id)).await else {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()));
+        };
+        if let Some(key) = &key {
+            let tag = req
+                .headers()
+                .get(PACKET_TAG)
+                .and_then(|v| v.to_str().ok())
+                .and_then(auth::from_hex);
+            if !tag.is_some_and(|tag| auth::verify(key, &body, &tag)) {
+                return Ok(HttpResponse::Unauthorized().finish());
+            }
+        }
+
+        // encoded like the device would, the tag is checked again on ingestion
+        let protection = key
+            .as_ref()
+            .map_or(Protection::Plain, Protection::Authenticated);
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        match packet.encode(&mut buf, protection) {
+            Ok(buf) => buf.to_vec(),
+            Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+        }
+    } else {
+        body.to_vec()
+    };
+
+    let source = req
+        .peer_addr()
+        .map_or_else(|| "http".to_owned(), |addr| addr.to_string());
+    match ingest.ingest(&datagram, &source).await {
+        Ok(accepted) => Ok(HttpResponse::Ok().json(IngestResponse {
+            duplicate: accepted.duplicate,
+            ack: ingest.ack(&accepted.header).await.ok().flatten(),
+        })),
+        Err(err @ IngestError::Decode(_)) => Ok(HttpResponse::BadRequest().body(err.to_string())),
+        Err(
+            IngestError::Unauthenticated(_)
+            | IngestError::Replayed(..)
+            | IngestError::ReplayedBoot(..),
+        ) => Ok(HttpResponse::Unauthorized().finish()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string())),
+    }
+}
+
+/// Queues a command, sent after the next report of the device
+#[post("/api/devices/{device_id}/commands")]
+async fn api_device_queue_command(
+    req: HttpRequest,
+    admin: web::Data<AdminToken>,
+    device_id: web::Path<u32>,
+    command: web::Json<Command>,
+    db: web::Data<Pool>,
+) -> io::Result<HttpResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let device_id = *device_id;
+    let command = command.into_inner();
+    if let Ok(res) = db
+        .run(move |db| db.insert_command(device_id, &command))
+        .await
+    {
+        return Ok(HttpResponse::Ok().json(res));
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct CommandsQuery {
+    limit: Option<u32>,
+}
+
+#[get("/api/devices/{device_id}/commands")]
+async fn api_device_commands(
+    device_id: web::Path<u32>,
+    query: web::Query<CommandsQuery>,
+    db: web::Data<Pool>,
+) -> io::Result<impl Responder> {
+    let device_id = *device_id;
+    let limit = query.limit.unwrap_or(100);
+    if let Ok(res) = db.run(move |db| db.commands(device_id, limit)).await {
+        return Ok(web::Json(res));
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct DiagnosticsQuery {
+    from_date: Option<u64>,
+    to_date: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[get("/api/devices/{device_id}/diagnostics")]
+async fn api_device_diagnostics(
+    device_id: web::Path<u32>,
+    query: web::Query<DiagnosticsQuery>,
+    db: web::Data<Pool>,
+) -> io::Result<impl Responder> {
+    let device_id = *device_id;
+    let query = query.into_inner();
+    let res = db
+        .run(move |db| {
+            db.diagnostics(
+                device_id,
+                query.from_date,
+                query.to_date,
+                query.limit.unwrap_or(100),
+            )
+        })
+        .await;
+    if let Ok(res) = res {
+        return Ok(web::Json(res));
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct EventsQuery {
+    device_id: Option<u32>,
+    kind: Option<String>, // e.g. "low_battery", see `Event::kind`
+    from_date: Option<u64>,
+    to_date: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[get("/api/events")]
+async fn api_events(
+    query: web::Query<EventsQuery>,
+    db: web::Data<Pool>,
+) -> io::Result<impl Responder> {
+    let query = query.into_inner();
+    let res = db
+        .run(move |db| {
+            db.events(
+                query.device_id,
+                query.kind.as_deref(),
+                query.from_date,
+                query.to_date,
+                query.limit.unwrap_or(100),
+            )
+        })
+        .await;
+    if let Ok(res) = res {
+        return Ok(web::Json(res));
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string()))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct SetDeviceKeyParams {
+    key: Option<String>, // hex, generated if omitted
+}
+
+#[put("/api/admin/devices/{device_id}/key")]
+async fn api_admin_set_device_key(
+    req: HttpRequest,
+    admin: web::Data<AdminToken>,
+    device_id: web::Path<u32>,
+    query: web::Query<SetDeviceKeyParams>,
+    db: web::Data<Pool>,
+) -> io::Result<HttpResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let key = match &query.key {
+        Some(hex) => match auth::key_from_hex(hex) {
+            Some(key) => key,
+            None => return Ok(HttpResponse::BadRequest().body("key must be 64 hex digits")),
+        },
+        None => rand::thread_rng().gen(),
+    };
+
+    let device_id = *device_id;
+    match db
+        .run(move |db| db.set_device_key(device_id, Some(&key)))
+        .await
+    {
+        Ok(true) => Ok(HttpResponse::Ok().json(auth::key_to_hex(&key))),
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string())),
+    }
+}
+
+#[delete("/api/admin/devices/{device_id}/key")]
+async fn api_admin_delete_device_key(
+    req: HttpRequest,
+    admin: web::Data<AdminToken>,
+    device_id: web::Path<u32>,
+    db: web::Data<Pool>,
+) -> io::Result<HttpResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let device_id = *device_id;
+    match db.run(move |db| db.set_device_key(device_id, None)).await {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "".to_string())),
+    }
+}
+
+/// Latest run of the retention job, not found until it ran once
+#[get("/api/admin/retention")]
+async fn api_admin_retention(
+    req: HttpRequest,
+    admin: web::Data<AdminToken>,
+    reports: web::Data<retention::Reports>,
+) -> io::Result<HttpResponse> {
+    if !is_admin(&req, &admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match reports.latest() {
+        Some(report) => Ok(HttpResponse::Ok().json(report)),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "".to_string())),
+    }
+}
+
+pub async fn new_http_server(
+    db: Pool,
+    ingest: ingest::Ingest,
+    retention: retention::Reports,
+    config: config::Http,
+) -> std::io::Result<()> {
+    let origins = config.cors_origins;
+    let admin = Data::new(AdminToken(config.admin_token));
     HttpServer::new(move || {
+        let mut cors = Cors::default()
+            .allowed_methods(vec!["GET", "PUT", "POST", "DELETE"])
+            .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+            .allowed_header(header::CONTENT_TYPE)
+            .allowed_header(PACKET_TAG)
+            .supports_credentials()
+            .max_age(3600);
+        for origin in &origins {
+            cors = match origin.as_str() {
+                "*" => cors.allow_any_origin(),
+                origin => cors.allowed_origin(origin),
+            };
+        }
+
         App::new()
             .app_data(Data::new(db.clone()))
+            .app_data(Data::new(ingest.clone()))
+            .app_data(Data::new(retention.clone()))
+            .app_data(admin.clone())
             .wrap(middleware::Compress::default())
+            .wrap_fn(|req, srv| {
+                let (method, start) = (req.method().to_string(), Instant::now());
+                let res = srv.call(req);
+                async move {
+                    let res = res.await?;
+                    let route = res.request().match_pattern();
+                    let status = res.status().as_u16();
+                    metrics::observe_request(&method, route.as_deref(), status, start.elapsed());
+                    Ok(res)
+                }
+            })
             .service(hello)
+            .service(api_metrics)
             .service(api_measurements_by_date)
             .service(api_measurements_all)
             .service(api_measurements_info)
             .service(api_known_devices)
+            .service(api_device_link_stats)
+            .service(api_ingest_stats)
+            .service(api_ingest)
+            .service(api_device_diagnostics)
+            .service(api_device_queue_command)
+            .service(api_device_commands)
+            .service(api_events)
             .service(api_set_device_name)
             .service(api_device_name)
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allowed_methods(vec!["GET", "PUT"])
-                    .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
-                    .allowed_header(header::CONTENT_TYPE)
-                    .supports_credentials()
-                    .max_age(3600),
-            )
+            .service(api_admin_set_device_key)
+            .service(api_admin_delete_device_key)
+            .service(api_admin_retention)
+            .wrap(cors)
     })
-    .bind(("0.0.0.0", 8081))?
+    .bind(config.bind)?
     .run()
     .await
 }
@@ -0,0 +1,504 @@
+//! Backend configuration, read from a TOML file and overridden by environment variables.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use log::LevelFilter;
+use serde::Deserialize;
+
+/// Used if no config file is given and it exists
+pub const DEFAULT_PATH: &str = "backend.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String, // off, error, warn, info, debug or trace, `RUST_LOG` is applied on top
+    pub database_url: Option<String>,
+    pub database_pool_size: u32, // connections shared by the API and the ingestion
+    pub udp: Udp,
+    pub http: Http,
+    pub features: Features,
+    pub mqtt: Mqtt,
+    pub influx: Influx,
+    pub retention: Retention,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Udp {
+    pub bind: SocketAddr, // sensor packets
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub bind: SocketAddr,            // REST API
+    pub cors_origins: Vec<String>,   // "*" allows any origin
+    pub admin_token: Option<String>, // `Authorization: Bearer <token>` of the admin routes
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub journal: bool,  // keep the raw datagrams
+    pub acks: bool,     // acknowledge received packets
+    pub commands: bool, // deliver queued commands
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mqtt {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: String, // `<prefix>/<device id>/state` and `/status`
+    pub discovery: bool,      // Home Assistant MQTT discovery
+    pub discovery_prefix: String, // as configured in Home Assistant
+    pub publish: bool,        // publish the measurements and status of the devices
+    pub sources: Vec<Source>, // third-party sensors to ingest
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Influx {
+    pub enabled: bool,
+    pub url: String, // write endpoint with the database or org and bucket, without the precision
+    pub token: Option<String>,
+    pub batch_size: usize,   // lines per request
+    pub flush_interval: u64, // s
+    pub max_buffered: usize, // lines kept while the target is down, the oldest are dropped
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    pub enabled: bool,
+    pub interval: u64, // s between runs, the first runs at startup
+    pub vacuum: bool,  // shrink the file once a quarter of it is free
+    // days to keep the measurements and their rollups, forever if unset
+    pub raw_days: Option<u32>,
+    pub hourly_days: Option<u32>,
+    pub daily_days: Option<u32>,
+    pub journal_days: Option<u32>, // raw datagrams of all devices, needed to reprocess
+    pub devices: Vec<DeviceRetention>, // replace the measurement days above for single devices
+}
+
+/// Days to keep the data of a device, forever if unset
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRetention {
+    pub device_id: u32,
+    pub raw_days: Option<u32>,
+    pub hourly_days: Option<u32>,
+    pub daily_days: Option<u32>,
+}
+
+/// Topic of a third-party sensor publishing JSON, e.g. Zigbee2MQTT or Tasmota
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub topic: String,
+    pub device_id: u32, // listed next to the UDP devices
+    pub fields: BTreeMap<Column, Field>,
+}
+
+/// Measurement columns a JSON field can be mapped onto
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    Temperature, // °C
+    Humidity,    // percent
+    Pressure,    // Pa
+    AirQuality,  // static IAQ index
+    BatV,        // V
+    BatCap,      // percent
+    Iaq,         // index
+    IaqAccuracy, // 0..3
+    Co2,         // ppm
+    Voc,         // ppm
+    RawGas,      // ohm
+}
+
+/// Dot separated path of the JSON field, e.g. `BME280.Temperature`,
+/// optionally with a factor converting it to the unit of the column
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Field {
+    Path(String),
+    Scaled { path: String, scale: f64 },
+}
+
+impl Field {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Path(path) | Self::Scaled { path, .. } => path,
+        }
+    }
+
+    pub fn scale(&self) -> f64 {
+        match self {
+            Self::Path(_) => 1.0,
+            Self::Scaled { scale, .. } => *scale,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_owned(),
+            database_url: None,
+            database_pool_size: 8,
+            udp: Udp::default(),
+            http: Http::default(),
+            features: Features::default(),
+            mqtt: Mqtt::default(),
+            influx: Influx::default(),
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 8989).into(),
+        }
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 8081).into(),
+            cors_origins: vec!["*".to_owned()],
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            journal: true,
+            acks: true,
+            commands: true,
+        }
+    }
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_owned(),
+            port: 1883,
+            client_id: "smart-meter-backend".to_owned(),
+            username: None,
+            password: None,
+            topic_prefix: "smart-meter".to_owned(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_owned(),
+            publish: true,
+            sources: Vec::new(),
+        }
+    }
+}
+
+impl Default for Influx {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: "http://localhost:8086/api/v2/write?org=home&bucket=smart-meter".to_owned(),
+            token: None,
+            batch_size: 5000,
+            flush_interval: 10,
+            max_buffered: 100_000,
+        }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 86400,
+            vacuum: true,
+            raw_days: None,
+            hourly_days: None,
+            daily_days: None,
+            journal_days: None,
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file (`DEFAULT_PATH` if it exists and none is given),
+    /// applies the environment overrides and validates the result
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = path.or_else(|| {
+            let default = Path::new(DEFAULT_PATH);
+            default.exists().then_some(default)
+        });
+
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("cannot read config {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("invalid config {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(level) = var("BACKEND_LOG_LEVEL") {
+            self.log_level = level;
+        }
+        if let Some(url) = var("DATABASE_URL") {
+            self.database_url = Some(url);
+        }
+        if let Some(size) = var("BACKEND_DATABASE_POOL_SIZE") {
+            self.database_pool_size = parse("BACKEND_DATABASE_POOL_SIZE", &size)?;
+        }
+        if let Some(bind) = var("BACKEND_UDP_BIND") {
+            self.udp.bind = parse("BACKEND_UDP_BIND", &bind)?;
+        }
+        if let Some(bind) = var("BACKEND_HTTP_BIND") {
+            self.http.bind = parse("BACKEND_HTTP_BIND", &bind)?;
+        }
+        if let Some(origins) = var("BACKEND_CORS_ORIGINS") {
+            self.http.cors_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_owned())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        if let Some(token) = var("ADMIN_TOKEN") {
+            self.http.admin_token = Some(token);
+        }
+        if let Some(enabled) = var("BACKEND_FEATURES_JOURNAL") {
+            self.features.journal = parse("BACKEND_FEATURES_JOURNAL", &enabled)?;
+        }
+        if let Some(enabled) = var("BACKEND_FEATURES_ACKS") {
+            self.features.acks = parse("BACKEND_FEATURES_ACKS", &enabled)?;
+        }
+        if let Some(enabled) = var("BACKEND_FEATURES_COMMANDS") {
+            self.features.commands = parse("BACKEND_FEATURES_COMMANDS", &enabled)?;
+        }
+        if let Some(enabled) = var("BACKEND_MQTT_ENABLED") {
+            self.mqtt.enabled = parse("BACKEND_MQTT_ENABLED", &enabled)?;
+        }
+        if let Some(host) = var("BACKEND_MQTT_HOST") {
+            self.mqtt.host = host;
+        }
+        if let Some(port) = var("BACKEND_MQTT_PORT") {
+            self.mqtt.port = parse("BACKEND_MQTT_PORT", &port)?;
+        }
+        if let Some(username) = var("BACKEND_MQTT_USERNAME") {
+            self.mqtt.username = Some(username);
+        }
+        if let Some(password) = var("BACKEND_MQTT_PASSWORD") {
+            self.mqtt.password = Some(password);
+        }
+        if let Some(prefix) = var("BACKEND_MQTT_TOPIC_PREFIX") {
+            self.mqtt.topic_prefix = prefix;
+        }
+        if let Some(enabled) = var("BACKEND_INFLUX_ENABLED") {
+            self.influx.enabled = parse("BACKEND_INFLUX_ENABLED", &enabled)?;
+        }
+        if let Some(url) = var("BACKEND_INFLUX_URL") {
+            self.influx.url = url;
+        }
+        if let Some(token) = var("BACKEND_INFLUX_TOKEN") {
+            self.influx.token = Some(token);
+        }
+        if let Some(enabled) = var("BACKEND_RETENTION_ENABLED") {
+            self.retention.enabled = parse("BACKEND_RETENTION_ENABLED", &enabled)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.log_level_filter()?;
+
+        match &self.database_url {
+            Some(url) if !url.trim().is_empty() => (),
+            _ => bail!("no database configured, set `database_url` or DATABASE_URL"),
+        }
+
+        if self.database_pool_size == 0 {
+            bail!("`database_pool_size` must be at least 1");
+        }
+
+        if self.udp.bind == self.http.bind {
+            bail!("`udp.bind` and `http.bind` are both {}", self.udp.bind);
+        }
+
+        for origin in &self.http.cors_origins {
+            let valid = origin == "*"
+                || ["http://", "https://"]
+                    .iter()
+                    .filter_map(|scheme| origin.strip_prefix(scheme))
+                    .any(|host| !host.is_empty() && !host.contains('/'));
+            if !valid {
+                bail!(
+                    "invalid CORS origin '{origin}' in `http.cors_origins`, \
+                     expected '*' or scheme://host[:port]"
+                );
+            }
+        }
+
+        if self
+            .http
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            bail!("`http.admin_token` is empty, leave it unset to disable the admin routes");
+        }
+
+        if self.mqtt.enabled {
+            if self.mqtt.host.trim().is_empty() {
+                bail!("`mqtt.host` is empty");
+            }
+            for (key, prefix) in [
+                ("mqtt.topic_prefix", &self.mqtt.topic_prefix),
+                ("mqtt.discovery_prefix", &self.mqtt.discovery_prefix),
+            ] {
+                let valid =
+                    !prefix.is_empty() && !prefix.ends_with('/') && !prefix.contains(['+', '#']);
+                if !valid {
+                    bail!(
+                        "invalid topic prefix '{prefix}' in `{key}`, expected e.g. 'smart-meter'"
+                    );
+                }
+            }
+
+            let mut topics = HashSet::new();
+            let mut device_ids = HashSet::new();
+            for source in &self.mqtt.sources {
+                let topic = &source.topic;
+                if topic.is_empty() || topic.contains(['+', '#']) {
+                    bail!("invalid topic '{topic}' in `mqtt.sources`, wildcards are not supported");
+                }
+                if !topics.insert(topic) {
+                    bail!("topic '{topic}' is listed twice in `mqtt.sources`");
+                }
+                if !device_ids.insert(source.device_id) {
+                    bail!(
+                        "device id {} is used twice in `mqtt.sources`",
+                        source.device_id
+                    );
+                }
+                if source.fields.is_empty() {
+                    bail!("no fields mapped for topic '{topic}' in `mqtt.sources`");
+                }
+                if let Some((_, field)) = source
+                    .fields
+                    .iter()
+                    .find(|(_, field)| field.path().split('.').any(str::is_empty))
+                {
+                    bail!(
+                        "invalid field path '{}' for topic '{topic}' in `mqtt.sources`",
+                        field.path()
+                    );
+                }
+            }
+        }
+
+        if self.influx.enabled {
+            let url = &self.influx.url;
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("invalid URL '{url}' in `influx.url`, expected http(s)://host/write?...");
+            }
+            if url.contains("precision=") {
+                bail!("`influx.url` must not set the precision, timestamps are sent in ms");
+            }
+            if self.influx.batch_size == 0 || self.influx.flush_interval == 0 {
+                bail!("`influx.batch_size` and `influx.flush_interval` must be at least 1");
+            }
+            if self.influx.max_buffered < self.influx.batch_size {
+                bail!("`influx.max_buffered` must be at least `influx.batch_size`");
+            }
+        }
+
+        if self.retention.enabled {
+            if self.retention.interval == 0 {
+                bail!("`retention.interval` must be at least 1");
+            }
+            let retention = &self.retention;
+            if [
+                retention.raw_days,
+                retention.hourly_days,
+                retention.daily_days,
+                retention.journal_days,
+            ]
+            .contains(&Some(0))
+            {
+                bail!("days in `retention` must be at least 1");
+            }
+            let mut device_ids = HashSet::new();
+            for device in &retention.devices {
+                if !device_ids.insert(device.device_id) {
+                    bail!(
+                        "device id {} is listed twice in `retention.devices`",
+                        device.device_id
+                    );
+                }
+                if [device.raw_days, device.hourly_days, device.daily_days].contains(&Some(0)) {
+                    bail!(
+                        "days of device {} in `retention.devices` must be at least 1",
+                        device.device_id
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn log_level_filter(&self) -> Result<LevelFilter> {
+        LevelFilter::from_str(&self.log_level).with_context(|| {
+            format!(
+                "invalid log level '{}', expected off, error, warn, info, debug or trace",
+                self.log_level
+            )
+        })
+    }
+
+    /// Validated by `load`
+    pub fn database_url(&self) -> &str {
+        self.database_url.as_deref().unwrap_or_default()
+    }
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+fn parse<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid value '{value}' of {name}"))
+}
@@ -1,12 +1,73 @@
 use crate::{schema::*, utils};
 use anyhow::Result;
-use common::req;
+use common::{
+    packet::{self, auth, crypto},
+    req,
+};
+use diesel::connection::{AnsiTransactionManager, SimpleConnection, TransactionManager};
 use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, PooledConnection};
 use diesel::sqlite::SqliteConnection;
-use std::env;
+use std::collections::HashMap;
 
 use self::models::DeviceName;
 
+/// Series of `Db::measurements_by_date` and their columns
+const SERIES: [(req::MeasurementType, &str); 13] = [
+    (req::MeasurementType::Temperature, "temperature"),
+    (req::MeasurementType::Pressure, "pressure"),
+    (req::MeasurementType::Humidity, "humidity"),
+    (req::MeasurementType::BatCapacity, "bat_cap"),
+    (req::MeasurementType::BatVoltage, "bat_v"),
+    (req::MeasurementType::AirQuality, "air_quality"),
+    (req::MeasurementType::Iaq, "iaq"),
+    (req::MeasurementType::IaqAccuracy, "iaq_accuracy"),
+    (req::MeasurementType::Co2, "co2"),
+    (req::MeasurementType::Voc, "voc"),
+    (req::MeasurementType::RawGas, "raw_gas"),
+    (
+        req::MeasurementType::StabilizationStatus,
+        "stabilization_status",
+    ),
+    (req::MeasurementType::RunInStatus, "run_in_status"),
+];
+
+/// Most measurements `Db::measurements_by_date` loads to downsample with LTTB
+const LTTB_MAX_ROWS: i64 = 100_000;
+
+/// Rollup tables and the width of their buckets in ms, finest first
+pub const ROLLUPS: [(&str, i64); 2] = [
+    ("measurements_hourly", 3_600_000),
+    ("measurements_daily", 86_400_000),
+];
+
+fn series_value(ty: req::MeasurementType, mes: &models::DeviceMeasurement) -> Option<f32> {
+    use req::MeasurementType::*;
+    match ty {
+        Temperature => mes.temperature,
+        Pressure => mes.pressure,
+        Humidity => mes.humidity,
+        BatCapacity => mes.bat_cap,
+        BatVoltage => mes.bat_v,
+        AirQuality => mes.air_quality,
+        Iaq => mes.iaq,
+        IaqAccuracy => mes.iaq_accuracy.map(|a| a as f32),
+        Co2 => mes.co2,
+        Voc => mes.voc,
+        RawGas => mes.raw_gas,
+        StabilizationStatus => mes.stabilization_status.map(f32::from),
+        RunInStatus => mes.run_in_status.map(f32::from),
+        DewPoint => None, // calculated by the frontend
+    }
+}
+
+/// Whether `Db::update_rollups` adds or removes a measurement
+#[derive(Clone, Copy, PartialEq)]
+enum Rollup {
+    Add,
+    Remove,
+}
+
 pub mod models {
     use super::*;
 
@@ -15,13 +76,42 @@ pub mod models {
     #[allow(unused)]
     pub struct NewDeviceMeasurement {
         pub device_id: i32,
-        pub timestamp: i64,           // ms since epoch
-        pub temperature: Option<f32>, // °C
-        pub humidity: Option<f32>,    // percent
-        pub pressure: Option<f32>,    // hPa
-        pub air_quality: Option<f32>, // ohm
-        pub bat_v: Option<f32>,       // V
-        pub bat_cap: Option<f32>,     // percent
+        pub timestamp: i64,            // ms since epoch
+        pub temperature: Option<f32>,  // °C
+        pub humidity: Option<f32>,     // percent
+        pub pressure: Option<f32>,     // hPa
+        pub air_quality: Option<f32>,  // static IAQ index
+        pub bat_v: Option<f32>,        // V
+        pub bat_cap: Option<f32>,      // percent
+        pub iaq: Option<f32>,          // index
+        pub iaq_accuracy: Option<i32>, // 0..3
+        pub co2: Option<f32>,          // ppm
+        pub voc: Option<f32>,          // ppm
+        pub raw_gas: Option<f32>,      // ohm
+        pub stabilization_status: Option<bool>,
+        pub run_in_status: Option<bool>,
+    }
+
+    impl NewDeviceMeasurement {
+        pub fn new(device_id: u32, timestamp: i64, mes: &packet::Measurement) -> Self {
+            Self {
+                device_id: device_id as i32,
+                timestamp,
+                temperature: mes.temperature,
+                pressure: mes.pressure,
+                humidity: mes.humidity,
+                air_quality: mes.air_quality,
+                bat_v: mes.bat_voltage,
+                bat_cap: mes.bat_capacity,
+                iaq: mes.iaq,
+                iaq_accuracy: mes.iaq_accuracy.map(i32::from),
+                co2: mes.co2,
+                voc: mes.voc,
+                raw_gas: mes.raw_gas,
+                stabilization_status: mes.stabilization_status,
+                run_in_status: mes.run_in_status,
+            }
+        }
     }
 
     #[derive(Debug, Default, Insertable, Queryable)]
@@ -32,21 +122,89 @@ pub mod models {
         pub name: String,
     }
 
-    #[derive(Debug, Queryable, serde::Serialize)]
+    #[derive(Debug, Clone, Queryable, serde::Serialize)]
     #[allow(unused)]
     pub struct DeviceMeasurement {
         pub device_id: i32,
-        pub timestamp: i64,           // ms since epoch
-        pub temperature: Option<f32>, // °C
-        pub humidity: Option<f32>,    // percent
-        pub pressure: Option<f32>,    // hPa
-        pub air_quality: Option<f32>, // ohm
-        pub bat_v: Option<f32>,       // V
-        pub bat_cap: Option<f32>,     // percent
+        pub timestamp: i64,            // ms since epoch
+        pub temperature: Option<f32>,  // °C
+        pub humidity: Option<f32>,     // percent
+        pub pressure: Option<f32>,     // hPa
+        pub air_quality: Option<f32>,  // static IAQ index
+        pub bat_v: Option<f32>,        // V
+        pub bat_cap: Option<f32>,      // percent
+        pub iaq: Option<f32>,          // index
+        pub iaq_accuracy: Option<i32>, // 0..3
+        pub co2: Option<f32>,          // ppm
+        pub voc: Option<f32>,          // ppm
+        pub raw_gas: Option<f32>,      // ohm
+        pub stabilization_status: Option<bool>,
+        pub run_in_status: Option<bool>,
+    }
+
+    /// Source types of the devices
+    pub const SOURCE_UDP: &str = "udp"; // our sensors, also when ingested over HTTP
+    pub const SOURCE_MQTT: &str = "mqtt"; // third-party sensors, see `mqtt::subscribe`
+
+    /// Time range of the rows of a table
+    #[derive(Debug, QueryableByName)]
+    pub struct TimeRange {
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+        pub first: Option<i64>, // ms since epoch
+        #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+        pub last: Option<i64>, // ms since epoch
     }
 
-    #[derive(Debug, Default, Insertable, Queryable, serde::Serialize)]
-    #[diesel(table_name=devices)]
+    #[derive(Debug, QueryableByName)]
+    pub struct DeviceId {
+        #[diesel(sql_type = diesel::sql_types::Integer)]
+        pub device_id: i32,
+    }
+
+    /// Size of the database file
+    #[derive(Debug, QueryableByName)]
+    pub struct PageCount {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        pub page_count: i64,
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        pub freelist_count: i64, // unused pages
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        pub page_size: i64, // bytes
+    }
+
+    /// Time bucket of `Db::measurements_by_date`
+    #[derive(Debug, QueryableByName)]
+    pub struct MeasurementBucket {
+        #[diesel(sql_type = diesel::sql_types::BigInt)]
+        pub timestamp: i64, // ms since epoch, center
+        #[diesel(sql_type = diesel::sql_types::Text)]
+        pub aggregates: String, // json, column of `SERIES` to avg, min and max
+    }
+
+    impl From<&NewDeviceMeasurement> for DeviceMeasurement {
+        fn from(mes: &NewDeviceMeasurement) -> Self {
+            Self {
+                device_id: mes.device_id,
+                timestamp: mes.timestamp,
+                temperature: mes.temperature,
+                humidity: mes.humidity,
+                pressure: mes.pressure,
+                air_quality: mes.air_quality,
+                bat_v: mes.bat_v,
+                bat_cap: mes.bat_cap,
+                iaq: mes.iaq,
+                iaq_accuracy: mes.iaq_accuracy,
+                co2: mes.co2,
+                voc: mes.voc,
+                raw_gas: mes.raw_gas,
+                stabilization_status: mes.stabilization_status,
+                run_in_status: mes.run_in_status,
+            }
+        }
+    }
+
+    #[derive(Debug, Default, Insertable, Queryable, Selectable, AsChangeset, serde::Serialize)]
+    #[diesel(table_name=devices, primary_key(device_id), treat_none_as_null = true)]
     #[allow(unused)]
     pub struct DeviceInfo {
         pub device_id: i32, // unique, key
@@ -57,38 +215,647 @@ pub mod models {
         pub report_interval: i32, // s
         pub sample_interval: i32, // s
         pub last_seen: i64,       // s
+        pub source: String,       // `SOURCE_UDP` or `SOURCE_MQTT`
+    }
+
+    #[derive(Debug, Insertable)]
+    #[diesel(table_name=device_diagnostics)]
+    #[allow(unused)]
+    pub struct NewDeviceDiagnostics {
+        pub device_id: i32,
+        pub timestamp: i64,    // ms since epoch
+        pub rssi: Option<i32>, // dBm
+        pub connect_attempts: i32,
+        pub connect_duration: i32, // ms
+        pub free_heap: i64,        // bytes
+        pub reset_reason: i32,     // see `packet::ResetReason`
+    }
+
+    impl NewDeviceDiagnostics {
+        pub fn new(device_id: u32, timestamp: i64, diag: &packet::Diagnostics) -> Self {
+            Self {
+                device_id: device_id as i32,
+                timestamp,
+                rssi: diag.rssi.map(i32::from),
+                connect_attempts: diag.connect_attempts as i32,
+                connect_duration: diag.connect_duration as i32,
+                free_heap: diag.free_heap as i64,
+                reset_reason: diag.reset_reason as i32,
+            }
+        }
+    }
+
+    #[derive(Debug, Queryable)]
+    #[allow(unused)]
+    pub struct DeviceDiagnostics {
+        pub id: i32,
+        pub device_id: i32,
+        pub timestamp: i64,
+        pub rssi: Option<i32>,
+        pub connect_attempts: i32,
+        pub connect_duration: i32,
+        pub free_heap: i64,
+        pub reset_reason: i32,
+    }
+
+    impl From<DeviceDiagnostics> for req::DeviceDiagnostics {
+        fn from(diag: DeviceDiagnostics) -> Self {
+            Self {
+                device_id: diag.device_id,
+                timestamp: diag.timestamp,
+                rssi: diag.rssi,
+                connect_attempts: diag.connect_attempts,
+                connect_duration: diag.connect_duration,
+                free_heap: diag.free_heap,
+                reset_reason: packet::ResetReason::from(diag.reset_reason as u8),
+            }
+        }
+    }
+
+    #[derive(Debug, Insertable)]
+    #[diesel(table_name=commands)]
+    #[allow(unused)]
+    pub struct NewCommand {
+        pub device_id: i32,
+        pub command: String, // json
+        pub status: i32,     // see `req::CommandStatus`
+        pub attempts: i32,
+        pub created_at: i64, // ms since epoch
+        pub updated_at: i64, // ms since epoch
+    }
+
+    #[derive(Debug, Queryable)]
+    #[allow(unused)]
+    pub struct Command {
+        pub id: i32,
+        pub device_id: i32,
+        pub command: String, // json
+        pub status: i32,     // see `req::CommandStatus`
+        pub attempts: i32,
+        pub created_at: i64, // ms since epoch
+        pub updated_at: i64, // ms since epoch
+    }
+
+    impl Command {
+        pub fn command(&self) -> Result<packet::command::Command> {
+            Ok(serde_json::from_str(&self.command)?)
+        }
+    }
+
+    impl TryFrom<Command> for req::DeviceCommand {
+        type Error = anyhow::Error;
+
+        fn try_from(cmd: Command) -> Result<Self> {
+            Ok(Self {
+                id: cmd.id,
+                device_id: cmd.device_id,
+                command: cmd.command()?,
+                status: req::CommandStatus::try_from(cmd.status)?,
+                attempts: cmd.attempts,
+                created_at: cmd.created_at,
+                updated_at: cmd.updated_at,
+            })
+        }
+    }
+
+    #[derive(Debug, Insertable)]
+    #[diesel(table_name=events)]
+    #[allow(unused)]
+    pub struct NewEvent {
+        pub device_id: i32,
+        pub timestamp: i64, // ms since epoch
+        pub kind: String,
+        pub event: String, // json
+    }
+
+    impl NewEvent {
+        pub fn new(device_id: u32, timestamp: i64, event: &packet::Event) -> Result<Self> {
+            Ok(Self {
+                device_id: device_id as i32,
+                timestamp,
+                kind: event.kind().to_owned(),
+                event: serde_json::to_string(event)?,
+            })
+        }
+    }
+
+    #[derive(Debug, Queryable)]
+    #[allow(unused)]
+    pub struct Event {
+        pub id: i32,
+        pub device_id: i32,
+        pub timestamp: i64, // ms since epoch
+        pub kind: String,
+        pub event: String, // json
+    }
+
+    #[derive(Debug, Insertable)]
+    #[diesel(table_name=journal)]
+    #[allow(unused)]
+    pub struct NewJournalEntry {
+        pub received_at: i64, // ms since epoch
+        pub source: String,
+        pub device_id: i32,
+        pub data: Vec<u8>, // datagram as received
+    }
+
+    #[derive(Debug, Queryable)]
+    #[allow(unused)]
+    pub struct JournalEntry {
+        pub id: i32,
+        pub received_at: i64, // ms since epoch
+        pub source: String,
+        pub device_id: i32,
+        pub data: Vec<u8>, // datagram as received
+    }
+
+    #[derive(Debug, Default, Insertable, Queryable)]
+    #[diesel(table_name=link_stats)]
+    #[allow(unused)]
+    pub struct LinkStats {
+        pub device_id: i32,
+        pub boot_id: i64,
+        pub last_sequence: i64,
+        pub seen_window: i64, // bit n set if `last_sequence - n` was received
+        pub received: i64,
+        pub missing: i64,
+        pub duplicates: i64,
+    }
+}
+
+/// Applied to every new connection of the pool
+#[derive(Debug)]
+struct Pragmas;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for Pragmas {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        // WAL lets the API read while the ingestion writes, writers wait for each other
+        conn.batch_execute(
+            "PRAGMA busy_timeout = 5000; PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;",
+        )
+        .map_err(r2d2::Error::QueryError)
+    }
+}
+
+#[derive(Clone)]
+pub struct Pool {
+    pool: r2d2::Pool<ConnectionManager<SqliteConnection>>,
+}
+
+impl Pool {
+    pub fn new(database_url: &str, size: u32) -> Result<Self> {
+        let pool = r2d2::Pool::builder()
+            .max_size(size)
+            .connection_customizer(Box::new(Pragmas))
+            .build(ConnectionManager::new(database_url))?;
+
+        Ok(Self { pool })
+    }
+
+    pub fn get(&self) -> Result<Db> {
+        Ok(Db {
+            conn: self.pool.get()?,
+        })
+    }
+
+    /// Runs `f` with a pooled connection on the blocking thread pool
+    pub async fn run<T, E>(
+        &self,
+        f: impl FnOnce(&mut Db) -> Result<T, E> + Send + 'static,
+    ) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<anyhow::Error> + Send + 'static,
+    {
+        let pool = self.clone();
+        tokio::task::spawn_blocking(move || f(&mut pool.get()?))
+            .await
+            .map_err(|err| E::from(err.into()))?
     }
 }
 
 pub struct Db {
-    conn: SqliteConnection,
+    conn: PooledConnection<ConnectionManager<SqliteConnection>>,
 }
 
 impl Db {
-    pub fn connect() -> Result<Self> {
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let conn = SqliteConnection::establish(&database_url)?;
+    /// Commits if `f` succeeds, nested calls use savepoints
+    pub fn transaction<T, E>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<T, E>
+    where
+        E: From<anyhow::Error>,
+    {
+        AnsiTransactionManager::begin_transaction(&mut *self.conn)
+            .map_err(|err| E::from(err.into()))?;
 
-        Ok(Self { conn })
+        match f(self) {
+            Ok(value) => {
+                AnsiTransactionManager::commit_transaction(&mut *self.conn)
+                    .map_err(|err| E::from(err.into()))?;
+                Ok(value)
+            }
+            Err(err) => {
+                AnsiTransactionManager::rollback_transaction(&mut *self.conn)
+                    .map_err(|err| E::from(err.into()))?;
+                Err(err)
+            }
+        }
     }
 
+    /// Replaces a measurement taken at the same time, e.g. when reprocessing the journal
     pub fn insert_measurement(&mut self, mes: &models::NewDeviceMeasurement) -> Result<()> {
-        println!("Insert into db!");
+        use crate::schema::measurements::dsl;
 
-        diesel::insert_into(measurements::table)
+        let replaced = dsl::measurements
+            .filter(dsl::device_id.eq(mes.device_id))
+            .filter(dsl::timestamp.eq(mes.timestamp))
+            .first::<models::DeviceMeasurement>(&mut self.conn)
+            .optional()?;
+        diesel::replace_into(measurements::table)
             .values(mes)
             .execute(&mut self.conn)?;
 
+        if let Some(replaced) = replaced {
+            self.update_rollups(&replaced, Rollup::Remove)?;
+        }
+        self.update_rollups(&mes.into(), Rollup::Add)?;
+
+        Ok(())
+    }
+
+    /// Adds the measurement to or removes it from the count, min, max and sum of its hour
+    /// and day. A removed minimum or maximum is recomputed from the other measurements of
+    /// the bucket, it stays if they were pruned already
+    fn update_rollups(&mut self, mes: &models::DeviceMeasurement, op: Rollup) -> Result<()> {
+        use diesel::sql_types::{BigInt, Double, Integer, Nullable};
+
+        for (table, width) in ROLLUPS {
+            let bucket = mes.timestamp.div_euclid(width) * width;
+            match op {
+                Rollup::Add => {
+                    let values = SERIES
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (_, column))| format!("('{column}', ?{})", i + 3))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let mut query = diesel::sql_query(format!(
+                        "INSERT INTO {table} (device_id, timestamp, field, count, min, max, sum) \
+                         SELECT ?1, ?2, column1, 1, column2, column2, column2 FROM (VALUES {values}) \
+                         WHERE column2 IS NOT NULL \
+                         ON CONFLICT (device_id, timestamp, field) DO UPDATE SET \
+                         count = count + 1, min = MIN(min, excluded.min), \
+                         max = MAX(max, excluded.max), sum = sum + excluded.sum"
+                    ))
+                    .into_boxed()
+                    .bind::<Integer, _>(mes.device_id)
+                    .bind::<BigInt, _>(bucket);
+                    for (ty, _) in SERIES {
+                        query =
+                            query.bind::<Nullable<Double>, _>(series_value(ty, mes).map(f64::from));
+                    }
+                    query.execute(&mut self.conn)?;
+                }
+                Rollup::Remove => {
+                    for (ty, column) in SERIES {
+                        let Some(value) = series_value(ty, mes) else {
+                            continue;
+                        };
+                        // the other measurements of the bucket, the removed one is replaced already
+                        let others = format!(
+                            "FROM measurements WHERE device_id = ?1 AND timestamp >= ?2 \
+                             AND timestamp < ?2 + {width} AND timestamp != ?4"
+                        );
+                        diesel::sql_query(format!(
+                            "UPDATE {table} SET count = count - 1, sum = sum - ?3, \
+                             min = CASE WHEN min < ?3 THEN min \
+                             ELSE COALESCE((SELECT MIN({column}) {others}), min) END, \
+                             max = CASE WHEN max > ?3 THEN max \
+                             ELSE COALESCE((SELECT MAX({column}) {others}), max) END \
+                             WHERE device_id = ?1 AND timestamp = ?2 AND field = '{column}'"
+                        ))
+                        .bind::<Integer, _>(mes.device_id)
+                        .bind::<BigInt, _>(bucket)
+                        .bind::<Double, _>(f64::from(value))
+                        .bind::<BigInt, _>(mes.timestamp)
+                        .execute(&mut self.conn)?;
+                    }
+
+                    diesel::sql_query(format!(
+                        "DELETE FROM {table} WHERE device_id = ? AND timestamp = ? AND count <= 0"
+                    ))
+                    .bind::<Integer, _>(mes.device_id)
+                    .bind::<BigInt, _>(bucket)
+                    .execute(&mut self.conn)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces an event of the same kind and time, e.g. when reprocessing the journal
+    pub fn insert_event(&mut self, event: &models::NewEvent) -> Result<()> {
+        use crate::schema::events::dsl;
+
+        self.conn.transaction(|conn| {
+            diesel::delete(
+                dsl::events
+                    .filter(dsl::device_id.eq(event.device_id))
+                    .filter(dsl::timestamp.eq(event.timestamp))
+                    .filter(dsl::kind.eq(&event.kind)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(events::table)
+                .values(event)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Most recent events first
+    pub fn events(
+        &mut self,
+        dev_id: Option<u32>,
+        kind_filter: Option<&str>,
+        from_date: Option<u64>,
+        to_date: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<req::DeviceEvent>> {
+        use crate::schema::events::dsl::*;
+
+        let mut query = events
+            .filter(timestamp.ge(from_date.unwrap_or(0) as i64))
+            .filter(timestamp.le(to_date.unwrap_or(utils::ms_since_epoch() as u64) as i64))
+            .into_boxed();
+        if let Some(dev_id) = dev_id {
+            query = query.filter(device_id.eq(dev_id as i32));
+        }
+        if let Some(kind_filter) = kind_filter {
+            query = query.filter(kind.eq(kind_filter.to_owned()));
+        }
+
+        let res = query
+            .order(timestamp.desc())
+            .limit(limit as i64)
+            .load::<models::Event>(&mut self.conn)?;
+
+        res.into_iter()
+            .map(|e| {
+                Ok(req::DeviceEvent {
+                    device_id: e.device_id,
+                    timestamp: e.timestamp,
+                    event: serde_json::from_str(&e.event)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces diagnostics of the same time, e.g. when reprocessing the journal
+    pub fn insert_diagnostics(&mut self, diag: &models::NewDeviceDiagnostics) -> Result<()> {
+        use crate::schema::device_diagnostics::dsl;
+
+        self.conn.transaction(|conn| {
+            diesel::delete(
+                dsl::device_diagnostics
+                    .filter(dsl::device_id.eq(diag.device_id))
+                    .filter(dsl::timestamp.eq(diag.timestamp)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(device_diagnostics::table)
+                .values(diag)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Most recent diagnostics first
+    pub fn diagnostics(
+        &mut self,
+        dev_id: u32,
+        from_date: Option<u64>,
+        to_date: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<req::DeviceDiagnostics>> {
+        use crate::schema::device_diagnostics::dsl::*;
+
+        let res = device_diagnostics
+            .filter(device_id.eq(dev_id as i32))
+            .filter(timestamp.ge(from_date.unwrap_or(0) as i64))
+            .filter(timestamp.le(to_date.unwrap_or(utils::ms_since_epoch() as u64) as i64))
+            .order(timestamp.desc())
+            .limit(limit as i64)
+            .load::<models::DeviceDiagnostics>(&mut self.conn)?;
+
+        Ok(res.into_iter().map(Into::into).collect())
+    }
+
+    pub fn insert_command(
+        &mut self,
+        dev_id: u32,
+        cmd: &packet::command::Command,
+    ) -> Result<req::DeviceCommand> {
+        let now = utils::ms_since_epoch() as i64;
+
+        diesel::insert_into(commands::table)
+            .values(&models::NewCommand {
+                device_id: dev_id as i32,
+                command: serde_json::to_string(cmd)?,
+                status: req::CommandStatus::Pending as i32,
+                attempts: 0,
+                created_at: now,
+                updated_at: now,
+            })
+            .get_result::<models::Command>(&mut self.conn)?
+            .try_into()
+    }
+
+    /// Most recent commands first
+    pub fn commands(&mut self, dev_id: u32, limit: u32) -> Result<Vec<req::DeviceCommand>> {
+        use crate::schema::commands::dsl;
+        dsl::commands
+            .filter(dsl::device_id.eq(dev_id as i32))
+            .order(dsl::id.desc())
+            .limit(limit as i64)
+            .load::<models::Command>(&mut self.conn)?
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect()
+    }
+
+    /// Pending commands and sent ones without ack since `resend_before`,
+    /// expires those that were sent `max_attempts` times
+    pub fn due_commands(
+        &mut self,
+        dev_id: u32,
+        resend_before: i64,
+        max_attempts: i32,
+    ) -> Result<Vec<models::Command>> {
+        use crate::schema::commands::dsl;
+        let unacknowledged = dsl::device_id
+            .eq(dev_id as i32)
+            .and(dsl::status.eq(req::CommandStatus::Sent as i32))
+            .and(dsl::updated_at.lt(resend_before));
+
+        diesel::update(dsl::commands.filter(unacknowledged.and(dsl::attempts.ge(max_attempts))))
+            .set((
+                dsl::status.eq(req::CommandStatus::Expired as i32),
+                dsl::updated_at.eq(utils::ms_since_epoch() as i64),
+            ))
+            .execute(&mut self.conn)?;
+
+        let pending = dsl::device_id
+            .eq(dev_id as i32)
+            .and(dsl::status.eq(req::CommandStatus::Pending as i32));
+        let res = dsl::commands
+            .filter(pending.or(unacknowledged))
+            .order(dsl::id.asc())
+            .load::<models::Command>(&mut self.conn)?;
+
+        Ok(res)
+    }
+
+    pub fn set_command_sent(&mut self, cmd_id: i32) -> Result<()> {
+        use crate::schema::commands::dsl;
+        diesel::update(dsl::commands.filter(dsl::id.eq(cmd_id)))
+            .set((
+                dsl::status.eq(req::CommandStatus::Sent as i32),
+                dsl::attempts.eq(dsl::attempts + 1),
+                dsl::updated_at.eq(utils::ms_since_epoch() as i64),
+            ))
+            .execute(&mut self.conn)?;
+        Ok(())
+    }
+
+    /// Only commands of the acknowledging device that await an ack are updated
+    pub fn acknowledge_command(&mut self, dev_id: u32, cmd_id: u32, accepted: bool) -> Result<()> {
+        use crate::schema::commands::dsl;
+        let status = match accepted {
+            true => req::CommandStatus::Acknowledged,
+            false => req::CommandStatus::Rejected,
+        };
+
+        diesel::update(
+            dsl::commands
+                .filter(dsl::id.eq(cmd_id as i32))
+                .filter(dsl::device_id.eq(dev_id as i32))
+                .filter(dsl::status.eq_any([
+                    req::CommandStatus::Sent as i32,
+                    req::CommandStatus::Expired as i32,
+                ])),
+        )
+        .set((
+            dsl::status.eq(status as i32),
+            dsl::updated_at.eq(utils::ms_since_epoch() as i64),
+        ))
+        .execute(&mut self.conn)?;
         Ok(())
     }
 
     pub fn update_device_info(&mut self, info: &models::DeviceInfo) -> Result<()> {
-        diesel::replace_into(devices::table)
-            .values(info)
+        use crate::schema::devices::dsl;
+
+        self.conn.transaction(|conn| {
+            // keep newer infos, e.g. when reprocessing the journal
+            let last_seen = dsl::devices
+                .filter(dsl::device_id.eq(info.device_id))
+                .select(dsl::last_seen)
+                .first::<i64>(conn)
+                .optional()?;
+            if last_seen.is_some_and(|last_seen| last_seen > info.last_seen) {
+                return Ok(());
+            }
+
+            // upsert, keeps the auth key of known devices
+            diesel::insert_into(devices::table)
+                .values(info)
+                .on_conflict(devices::device_id)
+                .do_update()
+                .set(info)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Registers a device of a third-party source on first sight, else updates when it was
+    /// last seen (s), returns false if the id belongs to a device of another source
+    pub fn touch_device(&mut self, dev_id: u32, source: &str, last_seen: i64) -> Result<bool> {
+        use crate::schema::devices::dsl;
+
+        self.conn.transaction(|conn| {
+            let known = dsl::devices
+                .filter(dsl::device_id.eq(dev_id as i32))
+                .select(dsl::source)
+                .first::<String>(conn)
+                .optional()?;
+            match known {
+                Some(known) if known != source => return Ok(false),
+                Some(_) => {
+                    diesel::update(
+                        dsl::devices
+                            .filter(dsl::device_id.eq(dev_id as i32))
+                            .filter(dsl::last_seen.lt(last_seen)),
+                    )
+                    .set(dsl::last_seen.eq(last_seen))
+                    .execute(conn)?;
+                }
+                None => {
+                    diesel::insert_into(devices::table)
+                        .values(&models::DeviceInfo {
+                            device_id: dev_id as i32,
+                            last_seen,
+                            source: source.to_owned(),
+                            ..Default::default()
+                        })
+                        .execute(conn)?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    pub fn device_key(&mut self, dev_id: u32) -> Result<Option<auth::Key>> {
+        use crate::schema::devices::dsl;
+        let key = dsl::devices
+            .filter(dsl::device_id.eq(dev_id as i32))
+            .select(dsl::auth_key)
+            .first::<Option<Vec<u8>>>(&mut self.conn)
+            .optional()?
+            .flatten();
+
+        Ok(key.and_then(|k| k.try_into().ok()))
+    }
+
+    /// Highest nonce of the encrypted packets of the device, persisted by `set_device_nonce`
+    pub fn device_nonce(&mut self, dev_id: u32) -> Result<Option<crypto::Nonce>> {
+        use crate::schema::devices::dsl;
+        let nonce = dsl::devices
+            .filter(dsl::device_id.eq(dev_id as i32))
+            .select(dsl::last_nonce)
+            .first::<Option<Vec<u8>>>(&mut self.conn)
+            .optional()?
+            .flatten();
+
+        Ok(nonce.and_then(|n| Some(crypto::Nonce::from_bytes(&n.try_into().ok()?))))
+    }
+
+    pub fn set_device_nonce(&mut self, dev_id: u32, nonce: crypto::Nonce) -> Result<()> {
+        use crate::schema::devices::dsl;
+        diesel::update(dsl::devices.filter(dsl::device_id.eq(dev_id as i32)))
+            .set(dsl::last_nonce.eq(nonce.to_bytes().to_vec()))
             .execute(&mut self.conn)?;
         Ok(())
     }
 
+    /// Returns false if the device is unknown
+    pub fn set_device_key(&mut self, dev_id: u32, key: Option<&auth::Key>) -> Result<bool> {
+        use crate::schema::devices::dsl;
+        let updated = diesel::update(dsl::devices.filter(dsl::device_id.eq(dev_id as i32)))
+            .set(dsl::auth_key.eq(key.map(|k| k.to_vec())))
+            .execute(&mut self.conn)?;
+
+        Ok(updated > 0)
+    }
+
     pub fn update_device_name(&mut self, device_name: &models::DeviceName) -> Result<()> {
         diesel::replace_into(device_names::table)
             .values(device_name)
@@ -96,6 +863,51 @@ impl Db {
         Ok(())
     }
 
+    pub fn insert_journal(&mut self, entry: &models::NewJournalEntry) -> Result<()> {
+        diesel::insert_into(journal::table)
+            .values(entry)
+            .execute(&mut self.conn)?;
+        Ok(())
+    }
+
+    /// Entries received between `from` and `to` (ms since epoch) after the entry `after_id`, oldest first
+    pub fn journal(
+        &mut self,
+        from: i64,
+        to: i64,
+        after_id: i32,
+        limit: u32,
+    ) -> Result<Vec<models::JournalEntry>> {
+        use crate::schema::journal::dsl::*;
+
+        let res = journal
+            .filter(received_at.ge(from))
+            .filter(received_at.le(to))
+            .filter(id.gt(after_id))
+            .order(id.asc())
+            .limit(limit as i64)
+            .load::<models::JournalEntry>(&mut self.conn)?;
+
+        Ok(res)
+    }
+
+    pub fn link_stats(&mut self, dev_id: u32) -> Result<Option<models::LinkStats>> {
+        use crate::schema::link_stats::dsl;
+        let stats = dsl::link_stats
+            .filter(dsl::device_id.eq(dev_id as i32))
+            .first::<models::LinkStats>(&mut self.conn)
+            .optional()?;
+
+        Ok(stats)
+    }
+
+    pub fn update_link_stats(&mut self, stats: &models::LinkStats) -> Result<()> {
+        diesel::replace_into(link_stats::table)
+            .values(stats)
+            .execute(&mut self.conn)?;
+        Ok(())
+    }
+
     pub fn device_name(&mut self, device_id: u32) -> Result<DeviceName> {
         use crate::schema::device_names::dsl;
         let device_name = dsl::device_names
@@ -105,6 +917,37 @@ impl Db {
         Ok(device_name)
     }
 
+    pub fn device_names(&mut self) -> Result<Vec<DeviceName>> {
+        use crate::schema::device_names::dsl;
+        let device_names = dsl::device_names.load::<models::DeviceName>(&mut self.conn)?;
+
+        Ok(device_names)
+    }
+
+    /// Most recent measurement of every device
+    pub fn latest_measurements(&mut self) -> Result<Vec<models::DeviceMeasurement>> {
+        use crate::schema::measurements::dsl::*;
+        let device_ids = measurements
+            .select(device_id)
+            .distinct()
+            .load::<i32>(&mut self.conn)?;
+
+        // one lookup per device, both use the primary key
+        let mut latest = Vec::with_capacity(device_ids.len());
+        for dev_id in device_ids {
+            latest.push(
+                measurements
+                    .filter(device_id.eq(dev_id))
+                    .order(timestamp.desc())
+                    .first::<models::DeviceMeasurement>(&mut self.conn)?,
+            );
+        }
+
+        Ok(latest)
+    }
+
+    /// Measurements of the requested types between `from_date` and `to_date`,
+    /// reduced to at most `limit` points, newest first
     pub fn measurements_by_date(
         &mut self,
         dev_id: u32,
@@ -112,74 +955,260 @@ impl Db {
         to_date: Option<u64>,
         measurement_type: u32,
         limit: u32,
+        downsampling: req::Downsampling,
     ) -> Result<req::MeasurementRequestResponse> {
         use crate::schema::measurements::dsl::*;
 
-        let total_entries: i64 = measurements
+        let from = from_date.unwrap_or(0) as i64;
+        let to = to_date.unwrap_or(utils::ms_since_epoch() as u64) as i64;
+        let series: Vec<_> = SERIES
+            .iter()
+            .filter(|(ty, _)| measurement_type & *ty as u32 > 0)
+            .collect();
+        // every requested series is listed, also without measurements
+        let empty = || {
+            series
+                .iter()
+                .map(|(ty, _)| (*ty as u32, Vec::new()))
+                .collect()
+        };
+        let mut resp = req::MeasurementRequestResponse {
+            device_id: dev_id as i32,
+            timestamps: Vec::new(),
+            data: empty(),
+            min: Default::default(),
+            max: Default::default(),
+        };
+
+        let range = measurements
             .filter(device_id.eq(dev_id as i32))
-            .filter(timestamp.ge(from_date.unwrap_or(0) as i64))
-            .filter(timestamp.le(to_date.unwrap_or(utils::ms_since_epoch() as u64) as i64))
-            .count()
-            .get_result(&mut self.conn)?;
+            .filter(timestamp.ge(from))
+            .filter(timestamp.le(to));
+        let rows = match downsampling {
+            req::Downsampling::Bucket => {
+                (resp.min, resp.max) = (empty(), empty());
+                // raw measurements and rollups, their older rows may have been pruned
+                let sources = [("measurements", 1), ROLLUPS[0], ROLLUPS[1]];
+                let ranges = sources
+                    .iter()
+                    .map(|(table, res)| {
+                        diesel::sql_query(format!(
+                            "SELECT MIN(timestamp) AS first, MAX(timestamp) AS last FROM {table} \
+                             WHERE device_id = ?1 AND timestamp > ?2 - {res} AND timestamp <= ?3"
+                        ))
+                        .bind::<diesel::sql_types::Integer, _>(dev_id as i32)
+                        .bind::<diesel::sql_types::BigInt, _>(from)
+                        .bind::<diesel::sql_types::BigInt, _>(to)
+                        .get_result::<models::TimeRange>(&mut self.conn)
+                    })
+                    .collect::<QueryResult<Vec<_>>>()?;
+                let first = ranges.iter().filter_map(|range| range.first).min();
+                let last = ranges.iter().filter_map(|range| range.last).max();
+                let (Some(first), Some(last)) = (first.map(|first| first.max(from)), last) else {
+                    return Ok(resp);
+                };
+                // at most `limit` buckets from the first to the last measurement
+                let width = (last - first) / limit.max(1) as i64 + 1;
 
-        let div = ((total_entries as f32 / limit as f32).ceil() as usize).max(1);
+                // the coarsest source whose buckets still fit into one point,
+                // or a coarser one if it does not reach back to the first measurement
+                let slack = ROLLUPS[ROLLUPS.len() - 1].1;
+                let mut i = sources
+                    .iter()
+                    .rposition(|(_, res)| *res <= width)
+                    .unwrap_or(0);
+                while i + 1 < sources.len() && ranges[i].first.is_none_or(|f| f > first + slack) {
+                    i += 1;
+                }
+                let rollup = (i > 0).then_some(sources[i]);
+                let query = match rollup {
+                    Some((table, res)) => diesel::sql_query(format!(
+                        "SELECT (MIN(first_start) + MAX(last_start) + {res}) / 2 AS timestamp, \
+                         json_group_object(field, json_array(avg, min, max)) AS aggregates \
+                         FROM (SELECT (timestamp - ?4) / ?5 AS bucket, field, \
+                         MIN(timestamp) AS first_start, MAX(timestamp) AS last_start, \
+                         SUM(sum) / SUM(count) AS avg, MIN(min) AS min, MAX(max) AS max \
+                         FROM {table} \
+                         WHERE device_id = ?1 AND timestamp > ?2 - {res} AND timestamp <= ?3 \
+                         GROUP BY bucket, field) \
+                         GROUP BY bucket \
+                         ORDER BY timestamp DESC"
+                    )),
+                    None => {
+                        let aggregates = SERIES
+                            .iter()
+                            .map(|(_, column)| {
+                                format!(
+                                    "'{column}', json_array(AVG({column}), MIN({column}), MAX({column}))"
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        diesel::sql_query(format!(
+                            "SELECT CAST(AVG(timestamp) AS INTEGER) AS timestamp, \
+                             json_object({aggregates}) AS aggregates \
+                             FROM measurements \
+                             WHERE device_id = ?1 AND timestamp >= ?2 AND timestamp <= ?3 \
+                             GROUP BY (timestamp - ?4) / ?5 \
+                             ORDER BY timestamp DESC"
+                        ))
+                    }
+                };
+                let buckets = query
+                    .bind::<diesel::sql_types::Integer, _>(dev_id as i32)
+                    .bind::<diesel::sql_types::BigInt, _>(from)
+                    .bind::<diesel::sql_types::BigInt, _>(to)
+                    .bind::<diesel::sql_types::BigInt, _>(first)
+                    .bind::<diesel::sql_types::BigInt, _>(width)
+                    .load::<models::MeasurementBucket>(&mut self.conn)?;
 
+                for bucket in buckets {
+                    // avg, min and max by column, rollups lack the columns without values
+                    let aggregates: HashMap<String, [Option<f32>; 3]> =
+                        serde_json::from_str(&bucket.aggregates)?;
+                    for (ty, column) in SERIES {
+                        let key = ty as u32;
+                        if let Some(avg) = resp.data.get_mut(&key) {
+                            let [a, min, max] = aggregates.get(column).copied().unwrap_or_default();
+                            avg.push(a);
+                            resp.min.entry(key).or_default().push(min);
+                            resp.max.entry(key).or_default().push(max);
+                        }
+                    }
+                    resp.timestamps.push(bucket.timestamp);
+                }
+                return Ok(resp);
+            }
+            req::Downsampling::Latest => range
+                .order(timestamp.desc())
+                .limit(limit as i64)
+                .load::<models::DeviceMeasurement>(&mut self.conn)?,
+            req::Downsampling::Lttb => {
+                // longer ranges are shortened to their latest measurements
+                let mut rows = range
+                    .order(timestamp.desc())
+                    .limit(LTTB_MAX_ROWS)
+                    .load::<models::DeviceMeasurement>(&mut self.conn)?;
+                rows.reverse();
+
+                // shaped by the first requested series with values
+                let points = series
+                    .iter()
+                    .map(|(ty, _)| {
+                        rows.iter()
+                            .enumerate()
+                            .filter_map(|(i, row)| {
+                                series_value(*ty, row).map(|value| (i, (row.timestamp, value)))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .find(|points| !points.is_empty())
+                    .unwrap_or_default();
+                let (indices, points): (Vec<_>, Vec<_>) = points.into_iter().unzip();
+                utils::lttb(&points, limit as usize)
+                    .into_iter()
+                    .rev()
+                    .map(|i| rows[indices[i]].clone())
+                    .collect()
+            }
+        };
+
+        for (ty, _) in &series {
+            let values = rows.iter().map(|row| series_value(*ty, row)).collect();
+            resp.data.insert(*ty as u32, values);
+        }
+        resp.timestamps = rows.iter().map(|row| row.timestamp).collect();
+        Ok(resp)
+    }
+
+    /// Measurements taken between `from` and `to` (ms since epoch) ordered by device and time,
+    /// following the (device id, timestamp) `after`
+    pub fn measurements_page(
+        &mut self,
+        from: i64,
+        to: i64,
+        after: (i32, i64),
+        limit: u32,
+    ) -> Result<Vec<models::DeviceMeasurement>> {
+        use crate::schema::measurements::dsl::*;
+
+        let (after_device_id, after_timestamp) = after;
         let res = measurements
-            .filter(device_id.eq(dev_id as i32))
-            .filter(timestamp.ge(from_date.unwrap_or(0) as i64))
-            .filter(timestamp.le(to_date.unwrap_or(utils::ms_since_epoch() as u64) as i64))
-            .order(timestamp.desc())
+            .filter(timestamp.ge(from))
+            .filter(timestamp.le(to))
+            .filter(
+                device_id.gt(after_device_id).or(device_id
+                    .eq(after_device_id)
+                    .and(timestamp.gt(after_timestamp))),
+            )
+            .order((device_id.asc(), timestamp.asc()))
+            .limit(limit as i64)
             .load::<models::DeviceMeasurement>(&mut self.conn)?;
 
-        // TODO: integrate this into the query somehow
-        let res: Vec<_> = res.iter().step_by(div).collect();
+        Ok(res)
+    }
 
-        // filter requested measurements
-        let mut data = std::collections::HashMap::new();
-        if measurement_type & req::MeasurementType::Temperature as u32 > 0 {
-            data.insert(
-                req::MeasurementType::Temperature as u32,
-                res.iter().map(|p| p.temperature).collect(),
-            );
-        }
-        if measurement_type & req::MeasurementType::Pressure as u32 > 0 {
-            data.insert(
-                req::MeasurementType::Pressure as u32,
-                res.iter().map(|p| p.pressure).collect(),
-            );
-        }
-        if measurement_type & req::MeasurementType::Humidity as u32 > 0 {
-            data.insert(
-                req::MeasurementType::Humidity as u32,
-                res.iter().map(|p| p.humidity).collect(),
-            );
-        }
-        if measurement_type & req::MeasurementType::BatCapacity as u32 > 0 {
-            data.insert(
-                req::MeasurementType::BatCapacity as u32,
-                res.iter().map(|p| p.bat_cap).collect(),
-            );
-        }
-        if measurement_type & req::MeasurementType::BatVoltage as u32 > 0 {
-            data.insert(
-                req::MeasurementType::BatVoltage as u32,
-                res.iter().map(|p| p.bat_v).collect(),
-            );
-        }
-        if measurement_type & req::MeasurementType::AirQuality as u32 > 0 {
-            data.insert(
-                req::MeasurementType::AirQuality as u32,
-                res.iter().map(|p| p.air_quality).collect(),
-            );
-        }
+    /// Devices with rows in any of `tables`
+    pub fn device_ids(&mut self, tables: &[&str]) -> Result<Vec<i32>> {
+        let query = tables
+            .iter()
+            .map(|table| format!("SELECT device_id FROM {table}"))
+            .collect::<Vec<_>>()
+            .join(" UNION ");
+        let ids = diesel::sql_query(query)
+            .load::<models::DeviceId>(&mut self.conn)?
+            .into_iter()
+            .map(|id| id.device_id)
+            .collect();
 
-        let resp = req::MeasurementRequestResponse {
-            device_id: dev_id as i32,
-            timestamps: res.iter().map(|p| p.timestamp).collect(),
-            data,
-        };
+        Ok(ids)
+    }
 
-        Ok(resp)
+    /// Deletes at most `limit` rows of `table` of the device older than `before` (ms since epoch),
+    /// small batches keep the ingestion from waiting for the write lock
+    pub fn prune(&mut self, table: &str, dev_id: i32, before: i64, limit: u32) -> Result<usize> {
+        let deleted = diesel::sql_query(format!(
+            "DELETE FROM {table} WHERE rowid IN \
+             (SELECT rowid FROM {table} WHERE device_id = ? AND timestamp < ? LIMIT ?)"
+        ))
+        .bind::<diesel::sql_types::Integer, _>(dev_id)
+        .bind::<diesel::sql_types::BigInt, _>(before)
+        .bind::<diesel::sql_types::Integer, _>(limit as i32)
+        .execute(&mut self.conn)?;
+
+        Ok(deleted)
+    }
+
+    /// Deletes at most `limit` journal entries received before `before` (ms since epoch)
+    pub fn prune_journal(&mut self, before: i64, limit: u32) -> Result<usize> {
+        let deleted = diesel::sql_query(
+            "DELETE FROM journal WHERE id IN \
+             (SELECT id FROM journal WHERE received_at < ? LIMIT ?)",
+        )
+        .bind::<diesel::sql_types::BigInt, _>(before)
+        .bind::<diesel::sql_types::Integer, _>(limit as i32)
+        .execute(&mut self.conn)?;
+
+        Ok(deleted)
+    }
+
+    pub fn page_count(&mut self) -> Result<models::PageCount> {
+        let count = diesel::sql_query(
+            "SELECT page_count, freelist_count, page_size \
+             FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        )
+        .get_result::<models::PageCount>(&mut self.conn)?;
+
+        Ok(count)
+    }
+
+    /// Rebuilds the database file without the unused pages, then truncates the WAL
+    /// that grew to the size of the database
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.conn
+            .batch_execute("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+
+        Ok(())
     }
 
     pub fn all_measurements(&mut self) -> Result<Vec<models::DeviceMeasurement>> {
@@ -213,8 +1242,76 @@ impl Db {
 
     pub fn devices(&mut self) -> Result<Vec<models::DeviceInfo>> {
         use crate::schema::devices::dsl;
-        let devices = dsl::devices.load::<models::DeviceInfo>(&mut self.conn)?;
+        let devices = dsl::devices
+            .select(models::DeviceInfo::as_select())
+            .load::<models::DeviceInfo>(&mut self.conn)?;
 
         Ok(devices)
     }
 }
+
+#[cfg(test)]
+impl Pool {
+    /// Empty database with all migrations applied, a file as the connections are pooled
+    pub fn for_test(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("backend-test-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = Self::new(path.to_str().unwrap(), 2).unwrap();
+
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let mut migrations: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().join("up.sql"))
+            .filter(|path| path.exists())
+            .collect();
+        migrations.sort();
+        let mut db = pool.get().unwrap();
+        for migration in migrations {
+            let sql = std::fs::read_to_string(migration).unwrap();
+            db.conn.batch_execute(&sql).unwrap();
+        }
+        pool
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hourly_temperature(db: &mut Db) -> (i32, f64, f64, f64) {
+        use crate::schema::measurements_hourly::dsl;
+
+        dsl::measurements_hourly
+            .filter(dsl::field.eq("temperature"))
+            .select((dsl::count, dsl::min, dsl::max, dsl::sum))
+            .first(&mut db.conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn replaced_bounds() {
+        let mut db = Pool::for_test("replaced_bounds").get().unwrap();
+        for (timestamp, temperature) in [(0, 10.0), (1000, 30.0), (2000, 20.0)] {
+            db.insert_measurement(&models::NewDeviceMeasurement {
+                device_id: 1,
+                timestamp,
+                temperature: Some(temperature),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        assert_eq!(hourly_temperature(&mut db), (3, 10.0, 30.0, 60.0));
+
+        for (timestamp, temperature) in [(1000, 25.0), (0, 15.0)] {
+            db.insert_measurement(&models::NewDeviceMeasurement {
+                device_id: 1,
+                timestamp,
+                temperature: Some(temperature),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        assert_eq!(hourly_temperature(&mut db), (3, 15.0, 25.0, 60.0));
+    }
+}
@@ -0,0 +1,307 @@
+//! InfluxDB line protocol: forwards the stored measurements to a write endpoint and
+//! exports the `measurements` table.
+//!
+//! Lines are `measurements,device_id=<id>[,name=<name>] <fields> <ms since epoch>`,
+//! the fields are the columns of `db::models::DeviceMeasurement` that are set.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    io,
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{config, db, db::models::DeviceMeasurement};
+
+const MEASUREMENT: &str = "measurements";
+const QUEUE_LEN: usize = 4096; // measurements, buffered by the push task
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+const EXPORT_PAGE_SIZE: u32 = 10_000;
+
+/// Formats the measurement as a line, None if no field is set
+pub fn line(mes: &DeviceMeasurement, name: Option<&str>) -> Option<String> {
+    let mut fields = String::new();
+    let floats = [
+        ("temperature", mes.temperature),
+        ("humidity", mes.humidity),
+        ("pressure", mes.pressure),
+        ("air_quality", mes.air_quality),
+        ("bat_v", mes.bat_v),
+        ("bat_cap", mes.bat_cap),
+        ("iaq", mes.iaq),
+        ("co2", mes.co2),
+        ("voc", mes.voc),
+        ("raw_gas", mes.raw_gas),
+    ];
+    // NaN and infinity cannot be written
+    for (key, value) in floats {
+        if let Some(value) = value.filter(|v| v.is_finite()) {
+            let _ = write!(fields, ",{key}={value}");
+        }
+    }
+    if let Some(accuracy) = mes.iaq_accuracy {
+        let _ = write!(fields, ",iaq_accuracy={accuracy}i");
+    }
+    for (key, value) in [
+        ("stabilization_status", mes.stabilization_status),
+        ("run_in_status", mes.run_in_status),
+    ] {
+        if let Some(value) = value {
+            let _ = write!(fields, ",{key}={value}");
+        }
+    }
+    let fields = fields.strip_prefix(',')?;
+
+    let mut line = format!("{MEASUREMENT},device_id={}", mes.device_id);
+    if let Some(name) = name.filter(|name| !name.is_empty()) {
+        let _ = write!(line, ",name={}", escape_tag(name));
+    }
+    Some(format!("{line} {fields} {}", mes.timestamp))
+}
+
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes the measurements taken between `from` and `to` (ms since epoch) as lines
+pub fn export(db: &db::Pool, from: i64, to: i64, out: &mut impl io::Write) -> Result<()> {
+    let mut db = db.get()?;
+    let names = device_names(&mut db)?;
+    let mut after = (i32::MIN, i64::MIN);
+    let mut exported = 0;
+
+    loop {
+        let page = db.measurements_page(from, to, after, EXPORT_PAGE_SIZE)?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = (last.device_id, last.timestamp);
+
+        for mes in &page {
+            let name = names.get(&mes.device_id).map(String::as_str);
+            if let Some(line) = line(mes, name) {
+                writeln!(out, "{line}")?;
+                exported += 1;
+            }
+        }
+    }
+
+    out.flush()?;
+    log::info!("Exported {} measurements", exported);
+    Ok(())
+}
+
+fn device_names(db: &mut db::Db) -> Result<HashMap<i32, String>> {
+    Ok(db
+        .device_names()?
+        .into_iter()
+        .map(|name| (name.device_id, name.name))
+        .collect())
+}
+
+/// Queues the stored measurements for the push task, never blocks the ingestion
+#[derive(Clone)]
+pub struct Pusher {
+    queue: mpsc::Sender<DeviceMeasurement>,
+}
+
+impl Pusher {
+    /// Starts the push task, it flushes what is left once all clones are dropped
+    pub fn start(config: config::Influx, db: db::Pool) -> (Self, JoinHandle<()>) {
+        let (queue, rx) = mpsc::channel(QUEUE_LEN);
+        let task = actix_web::rt::spawn(push(config, db, rx));
+        (Self { queue }, task)
+    }
+
+    pub fn push(&self, mes: DeviceMeasurement) {
+        if let Err(TrySendError::Full(mes)) = self.queue.try_send(mes) {
+            log::warn!(
+                "InfluxDB queue full, dropped measurement of device {}",
+                mes.device_id
+            );
+        }
+    }
+}
+
+/// Buffers the lines and writes them every flush interval or once a batch is full,
+/// failed writes are retried with an increasing delay
+async fn push(config: config::Influx, db: db::Pool, mut queue: mpsc::Receiver<DeviceMeasurement>) {
+    let client = reqwest::Client::new();
+    let separator = if config.url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{separator}precision=ms", config.url);
+    let interval = Duration::from_secs(config.flush_interval);
+    let mut buffer = VecDeque::new();
+    let mut names = HashMap::new();
+    let mut next_flush = Instant::now() + interval;
+    let mut retry_delay = None; // while the target is down
+
+    loop {
+        let closed = tokio::select! {
+            mes = queue.recv() => match mes {
+                Some(mes) => {
+                    if buffer.is_empty() {
+                        // names rarely change, refreshed for every batch
+                        match db.run(device_names).await {
+                            Ok(refreshed) => names = refreshed,
+                            Err(err) => log::warn!("Cannot read device names: {}", err),
+                        }
+                    }
+                    let name = names.get(&mes.device_id).map(String::as_str);
+                    if let Some(line) = line(&mes, name) {
+                        if buffer.len() == config.max_buffered {
+                            buffer.pop_front();
+                            log::warn!("InfluxDB buffer full, dropped the oldest line");
+                        }
+                        buffer.push_back(line);
+                    }
+                    // full batches are written right away, unless the target is down
+                    if buffer.len() < config.batch_size || retry_delay.is_some() {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = time::sleep_until(next_flush) => false,
+        };
+
+        while !buffer.is_empty() {
+            let len = buffer.len().min(config.batch_size);
+            let body = buffer.range(..len).cloned().collect::<Vec<_>>().join("\n");
+            match write(&client, &url, config.token.as_deref(), body).await {
+                Ok(()) => {
+                    buffer.drain(..len);
+                    retry_delay = None;
+                }
+                Err(err) if err.is::<Rejected>() => {
+                    log::error!("InfluxDB rejected {} lines: {}", len, err);
+                    buffer.drain(..len);
+                }
+                Err(err) => {
+                    let delay = retry_delay.map_or(interval, |delay| delay * 2);
+                    retry_delay = Some(delay.min(MAX_RETRY_DELAY));
+                    log::warn!(
+                        "Cannot write to InfluxDB, {} lines buffered: {}",
+                        buffer.len(),
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+
+        if closed {
+            if !buffer.is_empty() {
+                log::warn!("Dropped {} lines not written to InfluxDB", buffer.len());
+            }
+            break;
+        }
+        next_flush = Instant::now() + retry_delay.unwrap_or(interval);
+    }
+}
+
+/// Client error of the target, retrying would fail again
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+async fn write(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    body: String,
+) -> Result<()> {
+    let mut req = client.post(url).body(body);
+    if let Some(token) = token {
+        req = req.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+    }
+    let res = req.send().await?;
+
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let text = res.text().await.unwrap_or_default();
+    if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(Rejected(format!("{status}: {text}")).into());
+    }
+    bail!("{status}: {text}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::NewDeviceMeasurement;
+
+    fn measurement(mes: NewDeviceMeasurement) -> DeviceMeasurement {
+        (&NewDeviceMeasurement {
+            device_id: 42,
+            timestamp: 1_700_000_000_000,
+            ..mes
+        })
+            .into()
+    }
+
+    #[test]
+    fn fields() {
+        let mes = measurement(NewDeviceMeasurement {
+            temperature: Some(21.5),
+            humidity: Some(f32::NAN),
+            pressure: Some(f32::INFINITY),
+            iaq_accuracy: Some(3),
+            stabilization_status: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(
+            line(&mes, None).unwrap(),
+            "measurements,device_id=42 temperature=21.5,iaq_accuracy=3i,stabilization_status=true 1700000000000"
+        );
+    }
+
+    #[test]
+    fn no_fields() {
+        let mes = measurement(NewDeviceMeasurement {
+            temperature: Some(f32::NAN),
+            ..Default::default()
+        });
+        assert_eq!(line(&mes, Some("Kitchen")), None);
+    }
+
+    #[test]
+    fn escapes_name() {
+        let mes = measurement(NewDeviceMeasurement {
+            temperature: Some(20.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            line(&mes, Some("Living room, a=b")).unwrap(),
+            "measurements,device_id=42,name=Living\\ room\\,\\ a\\=b temperature=20 1700000000000"
+        );
+        // an empty tag value is invalid
+        assert_eq!(
+            line(&mes, Some("")).unwrap(),
+            "measurements,device_id=42 temperature=20 1700000000000"
+        );
+    }
+}
@@ -0,0 +1,996 @@
+//! Receives the sensor packets, stores them and answers with acks and queued commands.
+//!
+//! Bad packets are skipped and counted by reason, they never stop the ingestion.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use actix_web::rt::net::UdpSocket;
+use anyhow::{anyhow, Result};
+use common::{
+    packet::{
+        auth,
+        command::{CommandRequest, COMMAND_PORT},
+        crypto, Ack, DecodeError, Frame, Header, Packet, Payload, Protection, MAX_DATAGRAM_SIZE,
+    },
+    req::IngestStats,
+};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use tokio::{
+    signal,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{config, db, db::models::NewDeviceMeasurement, influx, metrics, mqtt, utils};
+
+const COMMAND_RESEND_AFTER: i64 = 60_000; // ms, without ack
+const COMMAND_MAX_ATTEMPTS: i32 = 3;
+const WRITE_QUEUE_LEN: usize = 4096; // packets, the receiver waits if the writer falls behind
+const MAX_BATCH_LEN: usize = 256; // packets per transaction
+
+#[derive(Debug)]
+pub enum IngestError {
+    Decode(DecodeError),
+    Unauthenticated(u32), // device id
+    Replayed(u32, crypto::Nonce),
+    ReplayedBoot(u32, u32), // device id, boot id older than the current one
+    InvalidField(&'static str, String),
+    Conflict(anyhow::Error), // constraint violated by the stored rows
+    Database(anyhow::Error),
+}
+
+impl From<DecodeError> for IngestError {
+    fn from(err: DecodeError) -> Self {
+        Self::Decode(err)
+    }
+}
+
+impl From<anyhow::Error> for IngestError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast_ref::<DieselError>() {
+            Some(DieselError::DatabaseError(
+                DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::ForeignKeyViolation,
+                _,
+            )) => Self::Conflict(err),
+            _ => Self::Database(err),
+        }
+    }
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "{err}"),
+            Self::Unauthenticated(device_id) => {
+                write!(f, "authentication failed for device {device_id}")
+            }
+            Self::Replayed(device_id, nonce) => {
+                write!(f, "replayed nonce {nonce:?} for device {device_id}")
+            }
+            Self::ReplayedBoot(device_id, boot_id) => {
+                write!(f, "replayed boot {boot_id} for device {device_id}")
+            }
+            Self::InvalidField(field, reason) => write!(f, "invalid {field}: {reason}"),
+            Self::Conflict(err) => write!(f, "conflict: {err}"),
+            Self::Database(err) => write!(f, "database: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+#[derive(Debug, Default)]
+struct Stats {
+    received: AtomicU64,
+    stored: AtomicU64,
+    duplicates: AtomicU64,
+    decode_errors: AtomicU64,
+    rejected: AtomicU64,
+    invalid_fields: AtomicU64,
+    db_conflicts: AtomicU64,
+    db_errors: AtomicU64,
+}
+
+impl Stats {
+    fn count(&self, err: &IngestError) {
+        let counter = match err {
+            IngestError::Decode(_) => &self.decode_errors,
+            IngestError::Unauthenticated(_)
+            | IngestError::Replayed(..)
+            | IngestError::ReplayedBoot(..) => &self.rejected,
+            IngestError::InvalidField(..) => &self.invalid_fields,
+            IngestError::Conflict(_) => &self.db_conflicts,
+            IngestError::Database(_) => &self.db_errors,
+        };
+        inc(counter);
+    }
+
+    fn snapshot(&self) -> IngestStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        IngestStats {
+            received: get(&self.received),
+            stored: get(&self.stored),
+            duplicates: get(&self.duplicates),
+            decode_errors: get(&self.decode_errors),
+            rejected: get(&self.rejected),
+            invalid_fields: get(&self.invalid_fields),
+            db_conflicts: get(&self.db_conflicts),
+            db_errors: get(&self.db_errors),
+        }
+    }
+}
+
+fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Decodes, deduplicates and queues the packets of the UDP listener and the HTTP endpoint
+#[derive(Clone)]
+pub struct Ingest {
+    db: db::Pool,
+    features: config::Features,
+    stats: Arc<Stats>,
+    nonces: Arc<Mutex<HashMap<u32, crypto::Nonce>>>,
+    queue: mpsc::Sender<Write>,
+    writer: WriterLock,
+}
+
+/// Held by the writer while it stores a batch, taken to pause it, e.g. during a vacuum
+#[derive(Clone, Default)]
+pub struct WriterLock(Arc<Mutex<()>>);
+
+impl WriterLock {
+    /// Waits for the running batch, the writer is paused until the guard is dropped
+    pub fn pause(&self) -> MutexGuard<'_, ()> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Accepted packet, duplicates are acknowledged again but not stored
+#[derive(Debug)]
+pub struct Accepted {
+    pub header: Header,
+    pub duplicate: bool,
+}
+
+/// Packet queued for the writer, see `Ingest::stored`
+pub struct Pending {
+    header: Header,
+    stored: oneshot::Receiver<Outcome>,
+}
+
+/// Outcome of a queued write, sent once its transaction committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Stored,
+    Duplicate,
+    Skipped, // received but its content was invalid, a retransmit would fail again
+}
+
+impl Ingest {
+    /// Starts the writer, it stores the queued packets until all clones are dropped
+    /// and forwards what was committed to MQTT and InfluxDB
+    pub fn new(
+        db: db::Pool,
+        features: config::Features,
+        mqtt: Option<mqtt::Publisher>,
+        influx: Option<influx::Pusher>,
+    ) -> (Self, JoinHandle<()>) {
+        let stats = Arc::new(Stats::default());
+        let (queue, rx) = mpsc::channel(WRITE_QUEUE_LEN);
+        let lock = WriterLock::default();
+        let writer = {
+            let (db, stats, lock) = (db.clone(), stats.clone(), lock.clone());
+            tokio::task::spawn_blocking(move || write(db, stats, mqtt, influx, lock, rx))
+        };
+
+        let ingest = Self {
+            db,
+            features,
+            stats,
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+            queue,
+            writer: lock,
+        };
+        (ingest, writer)
+    }
+
+    pub fn writer_lock(&self) -> WriterLock {
+        self.writer.clone()
+    }
+
+    pub fn stats(&self) -> IngestStats {
+        self.stats.snapshot()
+    }
+
+    /// Ingests a datagram as received from `source` and waits until it is stored
+    pub async fn ingest(&self, buf: &[u8], source: &str) -> Result<Accepted, IngestError> {
+        let pending = self.submit(buf, source).await?;
+        self.stored(pending).await
+    }
+
+    /// Decodes a datagram as received from `source` and queues it for the writer,
+    /// dropped packets are counted and logged
+    pub async fn submit(&self, buf: &[u8], source: &str) -> Result<Pending, IngestError> {
+        let received_at = utils::ms_since_epoch() as i64;
+        inc(&self.stats.received);
+
+        let (packet, nonce) = match self.decode(buf).await {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                self.stats.count(&err);
+                log::warn!("Dropped packet from {}: {}", source, err);
+                return Err(err);
+            }
+        };
+        let header = packet.header.clone();
+        let device_id = header.device_id;
+        if let Payload::Command(_) | Payload::Ack(_) = &packet.payload {
+            log::warn!("Ignored backend payload sent by device {}", device_id);
+        }
+
+        // keep the datagram, derived tables can be rebuilt from it
+        let journal = self.features.journal.then(|| db::models::NewJournalEntry {
+            received_at,
+            source: source.to_owned(),
+            device_id: device_id as i32,
+            data: buf.to_vec(),
+        });
+        let (reply, stored) = oneshot::channel();
+        let write = Write {
+            received_at,
+            journal,
+            record: Record::Packet(packet, nonce),
+            reply: Some(reply),
+        };
+        if self.queue.send(write).await.is_err() {
+            let err = IngestError::Database(anyhow!("writer stopped"));
+            log::error!("Dropped packet of device {}: {}", device_id, err);
+            return Err(err);
+        }
+
+        Ok(Pending { header, stored })
+    }
+
+    /// Waits for the transaction of the packet, it must not be acknowledged before.
+    /// Failed transactions are counted by the writer, the device sends the packet again
+    pub async fn stored(&self, pending: Pending) -> Result<Accepted, IngestError> {
+        let header = pending.header;
+        let Ok(outcome) = pending.stored.await else {
+            return Err(IngestError::Database(anyhow!(
+                "packet {} of device {} not stored",
+                header.sequence,
+                header.device_id
+            )));
+        };
+
+        let duplicate = outcome == Outcome::Duplicate;
+        if duplicate {
+            inc(&self.stats.duplicates);
+            log::info!(
+                "Dropped duplicate packet {} of device {}",
+                header.sequence,
+                header.device_id
+            );
+        }
+        Ok(Accepted { header, duplicate })
+    }
+
+    /// Ingests a measurement of a third-party sensor taken when received,
+    /// its device is registered on first sight
+    pub async fn ingest_measurement(&self, source: &'static str, mes: NewDeviceMeasurement) {
+        inc(&self.stats.received);
+        let write = Write {
+            received_at: mes.timestamp,
+            journal: None, // the journal only keeps datagrams
+            record: Record::Measurement(source, mes),
+            reply: None,
+        };
+        if self.queue.send(write).await.is_err() {
+            log::error!("Writer stopped, dropped measurement of a {} device", source);
+        }
+    }
+
+    /// Counts and logs a message of a third-party sensor that could not be mapped
+    pub fn reject(&self, source: &str, err: IngestError) {
+        inc(&self.stats.received);
+        self.stats.count(&err);
+        log::warn!("Dropped message from {}: {}", source, err);
+    }
+
+    /// Decodes the packet and returns the nonce if it was encrypted
+    async fn decode(&self, buf: &[u8]) -> Result<(Packet, Option<crypto::Nonce>), IngestError> {
+        let frame = Frame::parse(buf)?;
+        let device_id = frame.header.device_id;
+        let (key, stats, persisted) = self
+            .db
+            .run(move |db| {
+                anyhow::Ok((
+                    db.device_key(device_id)?,
+                    db.link_stats(device_id)?,
+                    db.device_nonce(device_id)?,
+                ))
+            })
+            .await?;
+
+        let packet = {
+            let mut nonces = self
+                .nonces
+                .lock()
+                .map_err(|_| IngestError::Database(anyhow!("nonces lock poisoned")))?;
+            // the persisted nonce lags behind until the writer stored the packets,
+            // but survives restarts
+            if let Some(persisted) = persisted {
+                let last = nonces.entry(device_id).or_insert(persisted);
+                *last = (*last).max(persisted);
+            }
+            decode_frame(&frame, key.as_ref(), Some(&mut nonces))?
+        };
+
+        // the boot id is covered by the tag and only ever increases, an earlier boot is
+        // a replay, duplicates of the current boot are dropped by the link statistics
+        let header = &packet.header;
+        if key.is_some()
+            && header.has_sequence()
+            && stats.is_some_and(|stats| (header.boot_id as i64) < stats.boot_id)
+        {
+            return Err(IngestError::ReplayedBoot(device_id, header.boot_id));
+        }
+
+        Ok((packet, frame.nonce()))
+    }
+
+    /// Acknowledges the packets of the current boot received without a gap,
+    /// None if acks are disabled, the packet has no sequence or the first one is missing
+    pub async fn ack(&self, header: &Header) -> Result<Option<Ack>> {
+        if !self.features.acks || !header.has_sequence() {
+            return Ok(None);
+        }
+
+        let device_id = header.device_id;
+        let stats = self.db.run(move |db| db.link_stats(device_id)).await?;
+        Ok(stats
+            .filter(|stats| stats.boot_id == header.boot_id as i64)
+            .and_then(|stats| stats.acknowledged())
+            .map(|sequence| Ack {
+                boot_id: header.boot_id,
+                sequence,
+            }))
+    }
+}
+
+/// Receives the UDP packets until ctrl-c, the device listens for acks and commands
+/// right after its report
+pub async fn listen(ingest: Ingest, sock: UdpSocket) {
+    let sock = Arc::new(sock);
+    let mut buf = [0; 1024];
+
+    // answered in order once stored, the next datagrams are queued in the meantime
+    let (answers, mut pending) = mpsc::channel::<(Pending, SocketAddr)>(WRITE_QUEUE_LEN);
+    let answer_task = {
+        let (ingest, sock) = (ingest.clone(), sock.clone());
+        actix_web::rt::spawn(async move {
+            while let Some((pending, addr)) = pending.recv().await {
+                answer(&ingest, &sock, pending, addr).await;
+            }
+        })
+    };
+
+    println!("Listening...");
+    loop {
+        tokio::select! {
+            Ok((len, addr)) = sock.recv_from(&mut buf) => {
+                let Ok(pending) = ingest.submit(&buf[0..len], &addr.to_string()).await else {
+                    continue;
+                };
+                if answers.send((pending, addr)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(()) = signal::ctrl_c() => { break; }
+        }
+    }
+
+    drop(answers);
+    let _ = answer_task.await;
+}
+
+/// Acknowledges the stored packet and sends the due commands
+async fn answer(ingest: &Ingest, sock: &UdpSocket, pending: Pending, addr: SocketAddr) {
+    let Ok(accepted) = ingest.stored(pending).await else {
+        return;
+    };
+    let device_id = accepted.header.device_id;
+
+    // also repeated for duplicates, the previous ack got lost
+    if let Err(err) = send_ack(ingest, sock, &accepted.header, addr).await {
+        log::warn!(
+            "Cannot acknowledge packets of device {}: {}",
+            device_id,
+            err
+        );
+    }
+    if ingest.features.commands && !accepted.duplicate {
+        if let Err(err) = send_commands(&ingest.db, sock, device_id, addr).await {
+            log::warn!("Cannot send commands to device {}: {}", device_id, err);
+        }
+    }
+}
+
+/// Accepted packet or measurement queued for the writer
+struct Write {
+    received_at: i64, // ms since epoch
+    journal: Option<db::models::NewJournalEntry>,
+    record: Record,
+    reply: Option<oneshot::Sender<Outcome>>, // after the commit
+}
+
+enum Record {
+    Packet(Packet, Option<crypto::Nonce>), // nonce if it was encrypted
+    Measurement(&'static str, NewDeviceMeasurement), // source type of the device, e.g. mqtt
+}
+
+/// Stores the queued packets, whatever queued up during a transaction goes into the next one
+fn write(
+    db: db::Pool,
+    stats: Arc<Stats>,
+    mqtt: Option<mqtt::Publisher>,
+    influx: Option<influx::Pusher>,
+    lock: WriterLock,
+    mut queue: mpsc::Receiver<Write>,
+) {
+    while let Some(write) = queue.blocking_recv() {
+        let mut batch = vec![write];
+        while batch.len() < MAX_BATCH_LEN {
+            match queue.try_recv() {
+                Ok(write) => batch.push(write),
+                Err(_) => break,
+            }
+        }
+
+        let running = lock.pause();
+        let timer = metrics::DB_INSERT_SECONDS.start_timer();
+        let res = db.get().map_err(IngestError::from).and_then(|mut db| {
+            db.transaction(|db| {
+                Ok(batch
+                    .iter()
+                    .map(|w| write_record(db, &stats, w))
+                    .collect::<Vec<_>>())
+            })
+        });
+        timer.observe_duration();
+        drop(running);
+        let outcomes = match res {
+            Ok(outcomes) => outcomes,
+            Err(err) => {
+                // the replies are dropped, nothing gets acknowledged
+                stats.count(&err);
+                log::warn!("Cannot store {} packets: {}", batch.len(), err);
+                continue;
+            }
+        };
+
+        // only what was committed
+        for (write, outcome) in batch.into_iter().zip(outcomes) {
+            let Some(outcome) = outcome else {
+                continue;
+            };
+            if outcome == Outcome::Stored {
+                inc(&stats.stored);
+                match &write.record {
+                    Record::Packet(packet, _) => {
+                        if let Some(mqtt) = &mqtt {
+                            mqtt.publish(packet, write.received_at);
+                        }
+                        if let Some(influx) = &influx {
+                            for mes in measurements(packet, write.received_at) {
+                                influx.push((&mes).into());
+                            }
+                        }
+                    }
+                    Record::Measurement(_, mes) => {
+                        if let Some(influx) = &influx {
+                            influx.push(mes.into());
+                        }
+                    }
+                }
+            }
+            if let Some(reply) = write.reply {
+                let _ = reply.send(outcome);
+            }
+        }
+    }
+}
+
+/// Tracks, journals and stores the record in its own savepoint, None if it failed
+/// and has to be sent again
+fn write_record(db: &mut db::Db, stats: &Stats, write: &Write) -> Option<Outcome> {
+    let (device_id, res) = match &write.record {
+        Record::Packet(packet, nonce) => (
+            packet.header.device_id,
+            db.transaction(|db| write_packet(db, stats, packet, *nonce, write)),
+        ),
+        Record::Measurement(source, mes) => (
+            mes.device_id as u32,
+            db.transaction(|db| store_measurement(db, source, mes, write.received_at))
+                .map(|()| Outcome::Stored),
+        ),
+    };
+    match res {
+        Ok(outcome) => Some(outcome),
+        // received intact, a retransmit would fail again
+        Err(err @ IngestError::InvalidField(..)) => {
+            stats.count(&err);
+            log::warn!("Skipped record of device {}: {}", device_id, err);
+            Some(Outcome::Skipped)
+        }
+        Err(err) => {
+            stats.count(&err);
+            log::warn!("Skipped record of device {}: {}", device_id, err);
+            None
+        }
+    }
+}
+
+/// Link stats, nonce, journal and content of the packet commit together, so a packet is
+/// only seen once it is stored
+fn write_packet(
+    db: &mut db::Db,
+    stats: &Stats,
+    packet: &Packet,
+    nonce: Option<crypto::Nonce>,
+    write: &Write,
+) -> Result<Outcome, IngestError> {
+    // also for duplicates, a retransmit is encrypted with the next nonce
+    if let Some(nonce) = nonce {
+        db.set_device_nonce(packet.header.device_id, nonce)?;
+    }
+    if !track_packet(db, &packet.header)? {
+        return Ok(Outcome::Duplicate);
+    }
+
+    if let Some(entry) = &write.journal {
+        if let Err(err) = db.transaction(|db| db.insert_journal(entry)) {
+            let err = IngestError::from(err);
+            stats.count(&err);
+            log::warn!(
+                "Cannot journal packet of device {}: {}",
+                entry.device_id,
+                err
+            );
+        }
+    }
+
+    match db.transaction(|db| store_packet(db, packet, write.received_at)) {
+        Ok(()) => Ok(Outcome::Stored),
+        // tracked anyway, a retransmit would fail again
+        Err(err @ IngestError::InvalidField(..)) => {
+            stats.count(&err);
+            log::warn!(
+                "Skipped packet of device {}: {}",
+                packet.header.device_id,
+                err
+            );
+            Ok(Outcome::Skipped)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Decodes the packet of the frame, packets of devices with an enrolled key must be
+/// authenticated or encrypted, replayed nonces are rejected if `nonces` are tracked
+pub fn decode_frame(
+    frame: &Frame,
+    key: Option<&auth::Key>,
+    nonces: Option<&mut HashMap<u32, crypto::Nonce>>,
+) -> Result<Packet, IngestError> {
+    let device_id = frame.header.device_id;
+
+    match (key, frame.nonce()) {
+        (Some(key), Some(nonce)) => {
+            let packet = frame.decrypt(key)?;
+
+            // nonces only ever increase, anything else is a replay
+            if let Some(nonces) = nonces {
+                if nonces.get(&device_id).is_some_and(|last| nonce <= *last) {
+                    return Err(IngestError::Replayed(device_id, nonce));
+                }
+                nonces.insert(device_id, nonce);
+            }
+
+            Ok(packet)
+        }
+        (Some(key), None) => {
+            if !frame.verify(key) {
+                return Err(IngestError::Unauthenticated(device_id));
+            }
+            Ok(frame.packet()?)
+        }
+        (None, _) => Ok(frame.packet()?),
+    }
+}
+
+/// Stores the measurements, events and device infos of the packet,
+/// timestamps are derived from the receive time (ms since epoch)
+pub fn store_packet(db: &mut db::Db, packet: &Packet, received_at: i64) -> Result<(), IngestError> {
+    let device_id = packet.header.device_id;
+    // the offset is relative to the time the packet was sent, ignoring network latency
+    let timestamp = received_at + packet.header.rel_timestamp;
+
+    match &packet.payload {
+        Payload::Measurement(_) | Payload::CompactMeasurement(_) | Payload::MeasurementBatch(_) => {
+            for mes in measurements(packet, received_at) {
+                db.insert_measurement(&mes)?;
+            }
+        }
+        Payload::Event(event) => {
+            log::info!("Event from device {}: {:?}", device_id, event);
+            let event = db::models::NewEvent::new(device_id, timestamp, event)
+                .map_err(|err| IngestError::InvalidField("event", err.to_string()))?;
+            db.insert_event(&event)?;
+        }
+        Payload::DeviceInfo(info) => {
+            log::debug!("Device info of device {}: {:?}", device_id, info);
+            let wifi_ssid = info
+                .wifi_ssid
+                .map(|b| std::str::from_utf8(&b).map(str::to_owned))
+                .transpose()
+                .map_err(|err| IngestError::InvalidField("wifi_ssid", err.to_string()))?;
+            db.update_device_info(&db::models::DeviceInfo {
+                device_id: device_id as i32,
+                fw_version: format!(
+                    "{}.{}.{}.{}",
+                    info.firmware_version[0],
+                    info.firmware_version[1],
+                    info.firmware_version[2],
+                    info.firmware_version[3]
+                ),
+                bsec_version: format!(
+                    "{}.{}.{}.{}",
+                    info.bsec_version[0],
+                    info.bsec_version[1],
+                    info.bsec_version[2],
+                    info.bsec_version[3]
+                ),
+                wifi_ssid,
+                uptime: info.uptime as i32,
+                report_interval: info.report_interval as i32,
+                sample_interval: info.sample_interval as i32,
+                last_seen: received_at / 1000,
+                source: db::models::SOURCE_UDP.to_owned(),
+            })?;
+
+            // kept as time series, unlike the device info
+            if let Some(diag) = &info.diagnostics {
+                db.insert_diagnostics(&db::models::NewDeviceDiagnostics::new(
+                    device_id, timestamp, diag,
+                ))?;
+            }
+        }
+        Payload::CommandAck(ack) => db.acknowledge_command(device_id, ack.id, ack.accepted)?,
+        Payload::Command(_) | Payload::Ack(_) => (),
+    }
+
+    Ok(())
+}
+
+/// Measurements of the packet, timestamps are derived from the receive time (ms since epoch)
+fn measurements(packet: &Packet, received_at: i64) -> Vec<NewDeviceMeasurement> {
+    let device_id = packet.header.device_id;
+    let timestamp = received_at + packet.header.rel_timestamp;
+
+    match &packet.payload {
+        Payload::Measurement(mes) => vec![NewDeviceMeasurement::new(device_id, timestamp, mes)],
+        Payload::CompactMeasurement(mes) => {
+            vec![NewDeviceMeasurement::new(device_id, timestamp, &mes.into())]
+        }
+        Payload::MeasurementBatch(batch) => batch
+            .samples
+            .iter()
+            .map(|sample| {
+                let timestamp = timestamp + sample.offset as i64;
+                NewDeviceMeasurement::new(device_id, timestamp, &sample.measurement)
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Stores the measurement of a third-party sensor, refused if the device id is taken
+/// by a device of another source
+fn store_measurement(
+    db: &mut db::Db,
+    source: &str,
+    mes: &NewDeviceMeasurement,
+    received_at: i64,
+) -> Result<(), IngestError> {
+    if !db.touch_device(mes.device_id as u32, source, received_at / 1000)? {
+        return Err(IngestError::InvalidField(
+            "device_id",
+            format!("{} belongs to a device of another source", mes.device_id),
+        ));
+    }
+    db.insert_measurement(mes)?;
+    Ok(())
+}
+
+/// Updates the link statistics of the device, returns false for duplicates
+fn track_packet(db: &mut db::Db, header: &Header) -> Result<bool, IngestError> {
+    if !header.has_sequence() {
+        return Ok(true);
+    }
+
+    let (stats, is_new) = match db.link_stats(header.device_id)? {
+        Some(mut stats) => {
+            let is_new = stats.track(header.boot_id, header.sequence);
+            (stats, is_new)
+        }
+        None => (
+            db::models::LinkStats::new(header.device_id as i32, header.boot_id, header.sequence),
+            true,
+        ),
+    };
+    db.update_link_stats(&stats)?;
+
+    Ok(is_new)
+}
+
+/// Sends due commands of the device to its command port, authenticated if a key is enrolled
+async fn send_commands(
+    db: &db::Pool,
+    sock: &UdpSocket,
+    device_id: u32,
+    addr: SocketAddr,
+) -> Result<()> {
+    let resend_before = utils::ms_since_epoch() as i64 - COMMAND_RESEND_AFTER;
+    let (key, commands) = db
+        .run(move |db| {
+            anyhow::Ok((
+                db.device_key(device_id)?,
+                db.due_commands(device_id, resend_before, COMMAND_MAX_ATTEMPTS)?,
+            ))
+        })
+        .await?;
+
+    for cmd in commands {
+        let mut header = Header::new(device_id, utils::ms_since_epoch() as u64);
+        header.sequence = cmd.id as u32;
+        let packet = Packet {
+            header,
+            payload: Payload::Command(CommandRequest {
+                id: cmd.id as u32,
+                command: cmd.command()?,
+            }),
+        };
+
+        send_to_device(sock, key.as_ref(), &packet, addr).await?;
+        log::info!("Sent command {} to device {}", cmd.id, device_id);
+
+        db.run(move |db| db.set_command_sent(cmd.id)).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends the ack to the device, it keeps unacknowledged packets for its next report
+async fn send_ack(
+    ingest: &Ingest,
+    sock: &UdpSocket,
+    header: &Header,
+    addr: SocketAddr,
+) -> Result<()> {
+    let Some(ack) = ingest.ack(header).await? else {
+        return Ok(());
+    };
+
+    let device_id = header.device_id;
+    let key = ingest.db.run(move |db| db.device_key(device_id)).await?;
+    let packet = Packet {
+        header: Header::new(device_id, utils::ms_since_epoch() as u64),
+        payload: Payload::Ack(ack),
+    };
+    send_to_device(sock, key.as_ref(), &packet, addr).await
+}
+
+/// Sends the packet to the command port of the device, authenticated if a key is enrolled
+async fn send_to_device(
+    sock: &UdpSocket,
+    key: Option<&auth::Key>,
+    packet: &Packet,
+    addr: SocketAddr,
+) -> Result<()> {
+    let protection = key.map_or(Protection::Plain, Protection::Authenticated);
+    let mut buf = [0; MAX_DATAGRAM_SIZE];
+    let buf = packet.encode(&mut buf, protection)?;
+    sock.send_to(buf, (addr.ip(), COMMAND_PORT)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::packet::{Measurement, PROTOCOL_VERSION};
+
+    const DEVICE_ID: u32 = 42;
+    const KEY: auth::Key = [7; auth::KEY_LEN];
+
+    async fn ingest(name: &str, key: Option<&auth::Key>) -> Ingest {
+        let db = db::Pool::for_test(name);
+        if let Some(key) = key.copied() {
+            db.run(move |db| {
+                db.touch_device(DEVICE_ID, db::models::SOURCE_UDP, 0)?;
+                db.set_device_key(DEVICE_ID, Some(&key))
+            })
+            .await
+            .unwrap();
+        }
+        Ingest::new(db, config::Features::default(), None, None).0
+    }
+
+    fn packet(boot_id: u32, sequence: u32) -> Packet {
+        let mut header = Header::new(DEVICE_ID, 1000);
+        header.boot_id = boot_id;
+        header.sequence = sequence;
+        Packet {
+            header,
+            payload: Payload::Measurement(Measurement {
+                temperature: Some(21.5),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn encode(packet: &Packet, protection: Protection) -> Vec<u8> {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        packet.encode(&mut buf, protection).unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn stores() {
+        let ingest = ingest("stores", None).await;
+        let accepted = ingest
+            .ingest(&encode(&packet(1, 0), Protection::Plain), "test")
+            .await
+            .unwrap();
+        assert!(!accepted.duplicate);
+        let stats = ingest.stats();
+        assert_eq!((stats.received, stats.stored), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn bad_magic() {
+        let ingest = ingest("bad_magic", None).await;
+        let mut datagram = encode(&packet(1, 0), Protection::Plain);
+        datagram[0] = b'X';
+        let err = ingest.ingest(&datagram, "test").await.unwrap_err();
+        assert!(matches!(
+            err,
+            IngestError::Decode(DecodeError::InvalidMagic)
+        ));
+        let stats = ingest.stats();
+        assert_eq!(
+            (stats.received, stats.decode_errors, stats.stored),
+            (1, 1, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_version() {
+        let ingest = ingest("unknown_version", None).await;
+        let mut datagram = encode(&packet(1, 0), Protection::Plain);
+        datagram[4] = PROTOCOL_VERSION + 1;
+        let err = ingest.ingest(&datagram, "test").await.unwrap_err();
+        assert!(matches!(
+            err,
+            IngestError::Decode(DecodeError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+        assert_eq!(ingest.stats().decode_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated() {
+        let ingest = ingest("unauthenticated", Some(&KEY)).await;
+        let plain = encode(&packet(1, 0), Protection::Plain);
+        let err = ingest.ingest(&plain, "test").await.unwrap_err();
+        assert!(matches!(err, IngestError::Unauthenticated(DEVICE_ID)));
+        let wrong_key = encode(
+            &packet(1, 1),
+            Protection::Authenticated(&[8; auth::KEY_LEN]),
+        );
+        let err = ingest.ingest(&wrong_key, "test").await.unwrap_err();
+        assert!(matches!(err, IngestError::Unauthenticated(DEVICE_ID)));
+        let stats = ingest.stats();
+        assert_eq!((stats.received, stats.rejected, stats.stored), (2, 2, 0));
+
+        let tagged = encode(&packet(1, 2), Protection::Authenticated(&KEY));
+        ingest.ingest(&tagged, "test").await.unwrap();
+        assert_eq!(ingest.stats().stored, 1);
+    }
+
+    #[tokio::test]
+    async fn replayed_nonce() {
+        let ingest = ingest("replayed_nonce", Some(&KEY)).await;
+        let nonce = crypto::Nonce::new(1);
+        let datagram = encode(&packet(1, 0), Protection::Encrypted(&KEY, nonce));
+        ingest.ingest(&datagram, "test").await.unwrap();
+        let err = ingest.ingest(&datagram, "test").await.unwrap_err();
+        assert!(matches!(err, IngestError::Replayed(DEVICE_ID, n) if n == nonce));
+        let stats = ingest.stats();
+        assert_eq!((stats.stored, stats.rejected, stats.duplicates), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn replayed_nonce_after_restart() {
+        let db = db::Pool::for_test("replayed_nonce_after_restart");
+        db.run(|db| {
+            db.touch_device(DEVICE_ID, db::models::SOURCE_UDP, 0)?;
+            db.set_device_key(DEVICE_ID, Some(&KEY))?;
+            db.set_device_nonce(
+                DEVICE_ID,
+                crypto::Nonce {
+                    epoch: 1,
+                    counter: 5,
+                },
+            )
+        })
+        .await
+        .unwrap();
+        let ingest = Ingest::new(db, config::Features::default(), None, None).0;
+
+        let nonce = crypto::Nonce {
+            epoch: 1,
+            counter: 3,
+        };
+        let datagram = encode(&packet(1, 3), Protection::Encrypted(&KEY, nonce));
+        let err = ingest.ingest(&datagram, "test").await.unwrap_err();
+        assert!(matches!(err, IngestError::Replayed(DEVICE_ID, n) if n == nonce));
+        assert_eq!(ingest.stats().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn replayed_boot() {
+        let ingest = ingest("replayed_boot", Some(&KEY)).await;
+        let current = encode(&packet(5, 0), Protection::Authenticated(&KEY));
+        ingest.ingest(&current, "test").await.unwrap();
+        let earlier = encode(&packet(4, 9), Protection::Authenticated(&KEY));
+        let err = ingest.ingest(&earlier, "test").await.unwrap_err();
+        assert!(matches!(err, IngestError::ReplayedBoot(DEVICE_ID, 4)));
+        let stats = ingest.stats();
+        assert_eq!((stats.stored, stats.rejected), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn duplicate() {
+        let ingest = ingest("duplicate", None).await;
+        let datagram = encode(&packet(1, 0), Protection::Plain);
+        assert!(!ingest.ingest(&datagram, "test").await.unwrap().duplicate);
+        assert!(ingest.ingest(&datagram, "test").await.unwrap().duplicate);
+        let stats = ingest.stats();
+        assert_eq!((stats.received, stats.stored, stats.duplicates), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn invalid_field() {
+        let ingest = ingest("invalid_field", None).await;
+        let mut packet = packet(1, 0);
+        packet.payload = Payload::DeviceInfo(common::packet::DeviceInfo {
+            wifi_ssid: Some([0xff; 32]), // not utf8
+            ..Default::default()
+        });
+        let accepted = ingest
+            .ingest(&encode(&packet, Protection::Plain), "test")
+            .await
+            .unwrap();
+        assert!(!accepted.duplicate);
+        let stats = ingest.stats();
+        assert_eq!((stats.stored, stats.invalid_fields), (0, 1));
+    }
+}
@@ -0,0 +1,58 @@
+//! Raw journal of all accepted datagrams, the derived tables can be rebuilt from it
+//! after schema or parsing changes.
+
+use anyhow::Result;
+use common::packet::Frame;
+
+use crate::{
+    db,
+    ingest::{decode_frame, store_packet, IngestError},
+};
+
+const PAGE_SIZE: u32 = 1000;
+
+/// Decodes the datagrams received between `from` and `to` (ms since epoch) again and
+/// replaces the measurements, events, diagnostics and device infos derived from them
+pub fn reprocess(db: &db::Pool, from: i64, to: i64) -> Result<()> {
+    let mut db = db.get()?;
+    let mut after_id = 0;
+    let mut stored = 0;
+    let mut failed = 0;
+
+    loop {
+        let entries = db.journal(from, to, after_id, PAGE_SIZE)?;
+        let Some(last) = entries.last() else {
+            break;
+        };
+        after_id = last.id;
+
+        // one transaction per page, a savepoint per entry
+        db.transaction(|db| {
+            for entry in &entries {
+                let res = db.transaction(|db| {
+                    let frame = Frame::parse(&entry.data)?;
+                    let key = db.device_key(frame.header.device_id)?;
+                    // nonces were checked when the datagram was received
+                    let packet = decode_frame(&frame, key.as_ref(), None)?;
+                    store_packet(db, &packet, entry.received_at)
+                });
+                match res {
+                    Ok(()) => stored += 1,
+                    Err(err) => {
+                        failed += 1;
+                        log::warn!(
+                            "Cannot reprocess journal entry {} of device {}: {}",
+                            entry.id,
+                            entry.device_id,
+                            err
+                        );
+                    }
+                }
+            }
+            Ok::<_, IngestError>(())
+        })?;
+    }
+
+    log::info!("Reprocessed {} packets, {} failed", stored, failed);
+    Ok(())
+}
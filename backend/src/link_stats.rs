@@ -0,0 +1,209 @@
+use crate::db::models::LinkStats;
+
+const WINDOW: i64 = 64; // bits of `seen_window`
+
+impl LinkStats {
+    pub fn new(device_id: i32, boot_id: u32, sequence: u32) -> Self {
+        Self {
+            device_id,
+            boot_id: boot_id as i64,
+            last_sequence: sequence as i64,
+            seen_window: 1,
+            received: 1,
+            missing: 0,
+            duplicates: 0,
+        }
+    }
+
+    /// Accounts a received packet, returns false if it is a duplicate
+    pub fn track(&mut self, boot_id: u32, sequence: u32) -> bool {
+        let boot_id = boot_id as i64;
+        let seq = sequence as i64;
+
+        // device rebooted, the sequence starts over
+        if boot_id != self.boot_id {
+            self.boot_id = boot_id;
+            self.last_sequence = seq;
+            self.seen_window = 1;
+            self.received += 1;
+            self.missing += seq;
+            return true;
+        }
+
+        if seq > self.last_sequence {
+            let gap = seq - self.last_sequence;
+            self.seen_window = if gap < WINDOW {
+                (self.seen_window << gap) | 1
+            } else {
+                1
+            };
+            self.last_sequence = seq;
+            self.received += 1;
+            self.missing += gap - 1;
+            return true;
+        }
+
+        // late packet, was counted as missing unless we have seen it already
+        let offset = self.last_sequence - seq;
+        if offset < WINDOW {
+            if self.seen_window & (1 << offset) == 0 {
+                self.seen_window |= 1 << offset;
+                self.received += 1;
+                self.missing = (self.missing - 1).max(0);
+                return true;
+            }
+            self.duplicates += 1;
+            return false;
+        }
+
+        // older than the window, cannot be told apart from a duplicate or a replay
+        self.duplicates += 1;
+        false
+    }
+
+    /// Highest sequence up to which all packets were received, what the device may drop.
+    /// A missing packet holds it back until it arrives or falls out of the window
+    pub fn acknowledged(&self) -> Option<u32> {
+        // offsets beyond the start of the boot do not exist
+        let oldest = self.last_sequence.min(WINDOW - 1);
+        let missing = (0..=oldest)
+            .rev()
+            .find(|offset| self.seen_window & (1 << offset) == 0);
+        match missing {
+            Some(offset) => u32::try_from(self.last_sequence - offset - 1).ok(),
+            None => Some(self.last_sequence as u32),
+        }
+    }
+}
+
+impl From<LinkStats> for common::req::LinkStats {
+    fn from(stats: LinkStats) -> Self {
+        Self {
+            device_id: stats.device_id,
+            received: stats.received,
+            missing: stats.missing,
+            duplicates: stats.duplicates,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(stats: &LinkStats) -> (i64, i64, i64) {
+        (stats.received, stats.missing, stats.duplicates)
+    }
+
+    #[test]
+    fn in_order() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        for seq in 1..100 {
+            assert!(stats.track(7, seq));
+        }
+        assert_eq!(counts(&stats), (100, 0, 0));
+        assert_eq!(stats.last_sequence, 99);
+        assert_eq!(stats.seen_window, -1);
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        assert!(stats.track(7, 1));
+        assert!(!stats.track(7, 1));
+        assert!(!stats.track(7, 0));
+        assert_eq!(counts(&stats), (2, 0, 2));
+    }
+
+    #[test]
+    fn reorder() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        assert!(stats.track(7, 3));
+        assert_eq!(counts(&stats), (2, 2, 0));
+        assert!(stats.track(7, 1));
+        assert!(stats.track(7, 2));
+        assert!(!stats.track(7, 2));
+        assert_eq!(counts(&stats), (4, 0, 1));
+        assert_eq!(stats.last_sequence, 3);
+        assert_eq!(stats.seen_window, 0b1111);
+    }
+
+    #[test]
+    fn rejects_beyond_window() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        for seq in 1..100 {
+            assert!(stats.track(7, seq));
+        }
+        // offset 98, out of the window, a duplicate or a replay
+        assert!(!stats.track(7, 1));
+        assert_eq!(counts(&stats), (100, 0, 1));
+        assert_eq!(stats.last_sequence, 99);
+    }
+
+    #[test]
+    fn gap() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        assert!(stats.track(7, 1));
+        assert!(stats.track(7, 100));
+        assert_eq!(counts(&stats), (3, 98, 0));
+        assert_eq!(stats.seen_window, 1);
+        // stragglers from before the gap, accepted within the window
+        assert!(stats.track(7, 50));
+        assert!(!stats.track(7, 2));
+        assert_eq!(counts(&stats), (4, 97, 1));
+        assert!(!stats.track(7, 50));
+        assert_eq!(stats.duplicates, 2);
+    }
+
+    #[test]
+    fn acknowledged() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        assert_eq!(stats.acknowledged(), Some(0));
+        assert!(stats.track(7, 1));
+        assert_eq!(stats.acknowledged(), Some(1));
+        // 2 and 4 lost
+        assert!(stats.track(7, 3));
+        assert!(stats.track(7, 5));
+        assert_eq!(stats.acknowledged(), Some(1));
+        assert!(stats.track(7, 2));
+        assert_eq!(stats.acknowledged(), Some(3));
+        assert!(stats.track(7, 4));
+        assert_eq!(stats.acknowledged(), Some(5));
+    }
+
+    #[test]
+    fn acknowledged_after_reboot() {
+        // the first packets of the boot were lost
+        let mut stats = LinkStats::new(1, 7, 2);
+        assert_eq!(stats.acknowledged(), None);
+        assert!(stats.track(7, 0));
+        assert_eq!(stats.acknowledged(), Some(0));
+        assert!(stats.track(7, 1));
+        assert_eq!(stats.acknowledged(), Some(2));
+    }
+
+    #[test]
+    fn acknowledged_gives_up_beyond_window() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        for seq in 2..=64 {
+            assert!(stats.track(7, seq));
+        }
+        assert_eq!(stats.acknowledged(), Some(0));
+        assert!(stats.track(7, 65));
+        assert_eq!(stats.acknowledged(), Some(65));
+    }
+
+    #[test]
+    fn reboot() {
+        let mut stats = LinkStats::new(1, 7, 0);
+        assert!(stats.track(7, 1));
+        assert!(stats.track(8, 0));
+        assert_eq!(stats.boot_id, 8);
+        assert_eq!((stats.last_sequence, stats.seen_window), (0, 1));
+        assert!(stats.track(8, 3));
+        assert_eq!(counts(&stats), (4, 2, 0));
+        // the sequence starts over, the previous boot does not count as duplicates
+        assert!(stats.track(8, 1));
+        assert_eq!(counts(&stats), (5, 1, 0));
+    }
+}
@@ -1,94 +1,121 @@
-use std::sync::{Arc, Mutex};
+use std::{io, path::PathBuf};
 
 use actix_web::rt::net::UdpSocket;
 use anyhow::Result;
-use common::packet::{Packet, Payload};
+use clap::{Parser, Subcommand, ValueEnum};
 use dotenvy::dotenv;
-use tokio::signal;
 
 mod api;
+mod config;
 mod db;
+mod influx;
+mod ingest;
+mod journal;
+mod link_stats;
+mod metrics;
+mod mqtt;
+mod retention;
 //mod req;
 mod schema;
 mod utils;
 
-#[actix_web::main]
-async fn main() -> Result<()> {
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
-    dotenv().ok();
-
-    let sock = UdpSocket::bind("0.0.0.0:8989").await?;
+/// Receives the sensor packets and serves the REST API
+#[derive(Parser)]
+struct Cli {
+    /// Config file, `backend.toml` is used if it exists
+    #[arg(long, global = true, env = "BACKEND_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let db = Arc::new(Mutex::new(db::Db::connect()?));
-    let web_db = db.clone();
+#[derive(Subcommand)]
+enum Command {
+    /// Rebuilds measurements, events, diagnostics and device infos from the packet journal
+    Reprocess {
+        /// Start of the receive time range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = utils::parse_date)]
+        from: Option<i64>,
+        /// End of the receive time range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = utils::parse_date)]
+        to: Option<i64>,
+    },
+    /// Writes the measurements to stdout
+    Export {
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+        /// Start of the measurement time range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = utils::parse_date)]
+        from: Option<i64>,
+        /// End of the measurement time range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = utils::parse_date)]
+        to: Option<i64>,
+    },
+}
 
-    let task = actix_web::rt::spawn(async move {
-        let mut buf = [0; 1024];
-        println!("Listening...");
-        loop {
-            tokio::select! {
-                Ok((len, _addr)) = sock.recv_from(&mut buf) => {
-                    // try deserialize
-                    let packet: postcard::Result<Packet> = postcard::from_bytes(&buf[0..len]);
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    /// InfluxDB line protocol, tagged with the device id and name
+    Influx,
+}
 
-                    if let Ok(packet) = packet {
-                        let device_id = packet.header.device_id;
+#[actix_web::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    let cli = Cli::parse();
+    let config = config::Config::load(cli.config.as_deref())?;
 
-                        // calculate timestamp of the packet
-                        // based on the offset relative to the time it was sent
-                        // ignoring network latency
-                        let timestamp = utils::utc_with_offset(packet.header.rel_timestamp);
+    env_logger::Builder::new()
+        .filter_level(config.log_level_filter()?)
+        .parse_env("RUST_LOG")
+        .init();
 
-                        match &packet.payload {
-                            Payload::Measurement(mes) => {
-                                if let Ok(mut db) = db.lock() {
-                                    db.insert_measurement(&db::models::NewDeviceMeasurement {
-                                        device_id: device_id as i32,
-                                        timestamp: timestamp.timestamp_millis(),
-                                        temperature: mes.temperature,
-                                        pressure: mes.pressure,
-                                        humidity: mes.humidity,
-                                        air_quality: mes.air_quality,
-                                        bat_v: mes.bat_voltage,
-                                        bat_cap: mes.bat_capacity,
-                                    })
-                                    .unwrap();
-                                }
-                            }
-                            Payload::DeviceInfo(info) => {
-                                if let Ok(mut db) = db.lock() {
-                                    db.update_device_info(&db::models::DeviceInfo {
-                                        device_id: device_id as i32,
-                                        fw_version: format!("{}.{}.{}.{}",
-                                            info.firmware_version[0],
-                                            info.firmware_version[1],
-                                            info.firmware_version[2],
-                                            info.firmware_version[3]),
-                                        bsec_version: format!("{}.{}.{}.{}",
-                                            info.bsec_version[0],
-                                            info.bsec_version[1],
-                                            info.bsec_version[2],
-                                            info.bsec_version[3]),
-                                        wifi_ssid: info.wifi_ssid.map(|b|std::str::from_utf8(&b).unwrap().to_owned()),
-                                        uptime: info.uptime as i32,
-                                        report_interval: info.report_interval as i32,
-                                        sample_interval: info.sample_interval as i32,
-                                        last_seen: utils::utc_with_offset(0).timestamp(),
-                                    })
-                                    .unwrap();
-                                }
-                                dbg!(info);
-                            },
-                        }
-                    }
+    let db = db::Pool::new(config.database_url(), config.database_pool_size)?;
 
-                }
-                Ok(()) = signal::ctrl_c() => { break; }
-            }
+    match cli.command {
+        Some(Command::Reprocess { from, to }) => {
+            let to = to.unwrap_or(utils::ms_since_epoch() as i64);
+            return journal::reprocess(&db, from.unwrap_or(0), to);
+        }
+        Some(Command::Export { format, from, to }) => {
+            let to = to.unwrap_or(utils::ms_since_epoch() as i64);
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            return match format {
+                ExportFormat::Influx => influx::export(&db, from.unwrap_or(0), to, &mut out),
+            };
         }
-    });
+        None => (),
+    }
+
+    let sock = UdpSocket::bind(config.udp.bind).await?;
+    let (publisher, subscriber) = if config.mqtt.enabled {
+        mqtt::start(config.mqtt.clone(), db.clone())
+    } else {
+        (None, None)
+    };
+    let (pusher, push_task) = config
+        .influx
+        .enabled
+        .then(|| influx::Pusher::start(config.influx.clone(), db.clone()))
+        .unzip();
+    let (ingest, writer) =
+        ingest::Ingest::new(db.clone(), config.features.clone(), publisher, pusher);
+    let retention = retention::start(config.retention.clone(), db.clone(), ingest.writer_lock());
+    let task = actix_web::rt::spawn(ingest::listen(ingest.clone(), sock));
+    let subscription = subscriber.map(|s| actix_web::rt::spawn(mqtt::subscribe(ingest.clone(), s)));
 
-    let _ = tokio::join!(api::new_http_server(web_db), task);
+    let _ = tokio::join!(
+        api::new_http_server(db, ingest, retention, config.http),
+        task
+    );
+    if let Some(subscription) = subscription {
+        let _ = subscription.await;
+    }
+    // stores the queued packets
+    let _ = writer.await;
+    // then pushes the stored measurements
+    if let Some(push_task) = push_task {
+        let _ = push_task.await;
+    }
     Ok(())
 }
@@ -0,0 +1,152 @@
+//! Prometheus metrics, served by `GET /metrics`.
+//!
+//! Latencies are recorded as they happen, the sensor gauges and the ingestion counters
+//! are read from the database and `ingest::Stats` on every scrape.
+
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use anyhow::Result;
+use common::req::IngestStats;
+use prometheus::{
+    register_histogram, register_histogram_vec, Encoder, GaugeVec, Histogram, HistogramVec,
+    IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::db::models::{DeviceMeasurement, DeviceName};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Transactions of the ingestion writer, each stores a batch of packets
+pub static DB_INSERT_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "smart_meter_db_insert_duration_seconds",
+        "Duration of the transactions storing the ingested packets",
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .expect("valid metric")
+});
+
+static HTTP_REQUEST_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "smart_meter_http_request_duration_seconds",
+        "Duration of the HTTP requests by route",
+        &["method", "route", "status"]
+    )
+    .expect("valid metric")
+});
+
+type Value = fn(&DeviceMeasurement) -> Option<f64>;
+
+/// Sensor gauges: name, help and value of the measurement
+const SENSORS: [(&str, &str, Value); 6] = [
+    (
+        "smart_meter_temperature_celsius",
+        "Latest temperature",
+        |mes| mes.temperature.map(f64::from),
+    ),
+    (
+        "smart_meter_humidity_percent",
+        "Latest relative humidity",
+        |mes| mes.humidity.map(f64::from),
+    ),
+    (
+        "smart_meter_pressure_pascals",
+        "Latest air pressure",
+        |mes| mes.pressure.map(f64::from),
+    ),
+    (
+        "smart_meter_iaq",
+        "Latest indoor air quality index",
+        |mes| mes.iaq.map(f64::from),
+    ),
+    (
+        "smart_meter_battery_percent",
+        "Latest battery capacity",
+        |mes| mes.bat_cap.map(f64::from),
+    ),
+    (
+        "smart_meter_measurement_timestamp_seconds",
+        "Time of the latest measurement",
+        |mes| Some(mes.timestamp as f64 / 1000.0),
+    ),
+];
+
+/// Records the duration of a request, `route` is the matched pattern to bound the label values
+pub fn observe_request(method: &str, route: Option<&str>, status: u16, duration: Duration) {
+    HTTP_REQUEST_SECONDS
+        .with_label_values(&[method, route.unwrap_or("unmatched"), &status.to_string()])
+        .observe(duration.as_secs_f64());
+}
+
+/// Renders all metrics in the Prometheus text format
+pub fn render(
+    measurements: &[DeviceMeasurement],
+    names: &[DeviceName],
+    stats: &IngestStats,
+) -> Result<String> {
+    // listed before their first sample
+    LazyLock::force(&DB_INSERT_SECONDS);
+    LazyLock::force(&HTTP_REQUEST_SECONDS);
+
+    let registry = Registry::new();
+    let names: HashMap<_, _> = names
+        .iter()
+        .map(|n| (n.device_id, n.name.as_str()))
+        .collect();
+    for (name, help, value) in SENSORS {
+        let gauge = GaugeVec::new(Opts::new(name, help), &["device_id", "name"])?;
+        for mes in measurements {
+            if let Some(value) = value(mes) {
+                let device_id = mes.device_id.to_string();
+                let name = names.get(&mes.device_id).copied().unwrap_or_default();
+                gauge.with_label_values(&[&device_id, name]).set(value);
+            }
+        }
+        registry.register(Box::new(gauge))?;
+    }
+
+    for (name, help, value) in [
+        (
+            "smart_meter_ingest_received_total",
+            "Datagrams and messages received",
+            stats.received,
+        ),
+        (
+            "smart_meter_ingest_stored_total",
+            "Packets stored",
+            stats.stored,
+        ),
+        (
+            "smart_meter_ingest_duplicates_total",
+            "Duplicate packets dropped",
+            stats.duplicates,
+        ),
+    ] {
+        let counter = IntCounter::new(name, help)?;
+        counter.inc_by(value);
+        registry.register(Box::new(counter))?;
+    }
+    let dropped = IntCounterVec::new(
+        Opts::new(
+            "smart_meter_ingest_dropped_total",
+            "Packets dropped by reason",
+        ),
+        &["reason"],
+    )?;
+    for (reason, value) in [
+        ("decode_error", stats.decode_errors),
+        ("rejected", stats.rejected),
+        ("invalid_field", stats.invalid_fields),
+        ("db_conflict", stats.db_conflicts),
+        ("db_error", stats.db_errors),
+    ] {
+        dropped.with_label_values(&[reason]).inc_by(value);
+    }
+    registry.register(Box::new(dropped))?;
+
+    let mut families = prometheus::gather();
+    families.extend(registry.gather());
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&families, &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
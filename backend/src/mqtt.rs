@@ -0,0 +1,396 @@
+//! Publishes the measurements and the status of the devices to an MQTT broker,
+//! along with the Home Assistant discovery config of each device, and ingests the
+//! measurements of third-party sensors (e.g. Zigbee2MQTT or Tasmota) from it.
+//!
+//! Topics (retained):
+//! * `<prefix>/availability`: `online` or `offline` (last will)
+//! * `<prefix>/<device id>/state`: latest measurement as JSON
+//! * `<prefix>/<device id>/status`: latest device info as JSON
+
+use std::{collections::HashSet, time::Duration};
+
+use common::packet::{self, Packet, Payload};
+use rumqttc::{AsyncClient, Event, Incoming, LastWill, MqttOptions, Publish, QoS};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    signal,
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::{
+    config::{self, Column},
+    db::{self, models::NewDeviceMeasurement},
+    ingest::{Ingest, IngestError},
+    utils,
+};
+
+const QUEUE_LEN: usize = 1024; // updates or messages, dropped while the queue is full
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Discovered sensors: key in the state, name, Home Assistant device class and unit,
+/// the BSEC IAQ index has no device class, `aqi` is the outdoor air quality index
+const SENSORS: [(&str, &str, Option<&str>, Option<&str>); 5] = [
+    (
+        "temperature",
+        "Temperature",
+        Some("temperature"),
+        Some("°C"),
+    ),
+    ("humidity", "Humidity", Some("humidity"), Some("%")),
+    (
+        "pressure",
+        "Pressure",
+        Some("atmospheric_pressure"),
+        Some("hPa"),
+    ),
+    ("iaq", "IAQ", None, None),
+    ("battery", "Battery", Some("battery"), Some("%")),
+];
+
+enum Update {
+    Connected,
+    State(u32, State),
+    Status(u32, Status),
+}
+
+#[derive(Debug, Serialize)]
+struct State {
+    timestamp: i64,               // ms since epoch
+    temperature: Option<f32>,     // °C
+    humidity: Option<f32>,        // percent
+    pressure: Option<f32>,        // hPa
+    iaq: Option<f32>,             // index
+    iaq_accuracy: Option<u8>,     // 0..3
+    co2: Option<f32>,             // ppm
+    voc: Option<f32>,             // ppm
+    battery: Option<f32>,         // percent
+    battery_voltage: Option<f32>, // V
+}
+
+impl State {
+    fn new(timestamp: i64, mes: &packet::Measurement) -> Self {
+        Self {
+            timestamp,
+            temperature: mes.temperature,
+            humidity: mes.humidity,
+            pressure: mes.pressure.map(|pa| pa / 100.0),
+            iaq: mes.iaq,
+            iaq_accuracy: mes.iaq_accuracy,
+            co2: mes.co2,
+            voc: mes.voc,
+            battery: mes.bat_capacity,
+            battery_voltage: mes.bat_voltage,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    timestamp: i64, // ms since epoch
+    fw_version: String,
+    uptime: u64,            // s
+    report_interval: u64,   // s
+    sample_interval: u64,   // s
+    rssi: Option<i8>,       // dBm
+    free_heap: Option<u32>, // bytes
+}
+
+/// Queues the updates for the connection task, never blocks the ingestion
+#[derive(Clone)]
+pub struct Publisher {
+    queue: mpsc::Sender<Update>,
+}
+
+/// Messages of the topics of the third-party sensors
+pub struct Subscriber {
+    sources: Vec<config::Source>,
+    messages: mpsc::Receiver<Publish>,
+}
+
+/// Connects in the background and reconnects until the backend stops, returns the
+/// publisher unless disabled and the subscriber if sources are configured
+pub fn start(config: config::Mqtt, db: db::Pool) -> (Option<Publisher>, Option<Subscriber>) {
+    let availability = format!("{}/availability", config.topic_prefix);
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if config.publish {
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+    }
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, QUEUE_LEN);
+    let (updates, updates_rx) = mpsc::channel(QUEUE_LEN);
+    let (messages, messages_rx) = mpsc::channel(QUEUE_LEN);
+    let topics: Vec<_> = config.sources.iter().map(|s| s.topic.clone()).collect();
+
+    let (subscriptions, connected) = (client.clone(), updates.clone());
+    actix_web::rt::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    log::info!("Connected to MQTT broker");
+                    // never wait here, the event loop drives the other tasks
+                    let _ = connected.try_send(Update::Connected);
+                    // the broker forgets them with the session
+                    for topic in &topics {
+                        if let Err(err) = subscriptions.try_subscribe(topic, QoS::AtLeastOnce) {
+                            log::warn!("Cannot subscribe to {}: {}", topic, err);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Incoming::Publish(msg))) => {
+                    if let Err(TrySendError::Full(_)) = messages.try_send(msg) {
+                        log::warn!("MQTT queue full, dropped message");
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    log::warn!("MQTT connection failed: {}", err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    let subscriber = (!config.sources.is_empty()).then(|| Subscriber {
+        sources: config.sources.clone(),
+        messages: messages_rx,
+    });
+    let publisher = config.publish.then(|| {
+        actix_web::rt::spawn(publish(client, config, db, updates_rx));
+        Publisher { queue: updates }
+    });
+    (publisher, subscriber)
+}
+
+impl Publisher {
+    /// Queues the measurements or the device info of the packet
+    pub fn publish(&self, packet: &Packet, received_at: i64) {
+        let device_id = packet.header.device_id;
+        // the offset is relative to the time the packet was sent, see `ingest::store_packet`
+        let timestamp = received_at + packet.header.rel_timestamp;
+
+        let updates = match &packet.payload {
+            Payload::Measurement(mes) => vec![Update::State(device_id, State::new(timestamp, mes))],
+            Payload::CompactMeasurement(mes) => {
+                vec![Update::State(device_id, State::new(timestamp, &mes.into()))]
+            }
+            Payload::MeasurementBatch(batch) => batch
+                .samples
+                .iter()
+                .map(|sample| {
+                    let timestamp = timestamp + sample.offset as i64;
+                    Update::State(device_id, State::new(timestamp, &sample.measurement))
+                })
+                .collect(),
+            Payload::DeviceInfo(info) => vec![Update::Status(
+                device_id,
+                Status {
+                    timestamp,
+                    fw_version: info.firmware_version.map(|v| v.to_string()).join("."),
+                    uptime: info.uptime,
+                    report_interval: info.report_interval,
+                    sample_interval: info.sample_interval,
+                    rssi: info.diagnostics.as_ref().and_then(|diag| diag.rssi),
+                    free_heap: info.diagnostics.as_ref().map(|diag| diag.free_heap),
+                },
+            )],
+            _ => vec![],
+        };
+
+        for update in updates {
+            if self.queue.try_send(update).is_err() {
+                log::warn!("MQTT queue full, dropped update of device {}", device_id);
+            }
+        }
+    }
+}
+
+async fn publish(
+    client: AsyncClient,
+    config: config::Mqtt,
+    db: db::Pool,
+    mut queue: mpsc::Receiver<Update>,
+) {
+    let prefix = &config.topic_prefix;
+    // announced since the last connect, the broker may have lost the retained configs
+    let mut announced = HashSet::new();
+
+    while let Some(update) = queue.recv().await {
+        let (device_id, topic, payload) = match update {
+            Update::Connected => {
+                announced.clear();
+                let topic = format!("{prefix}/availability");
+                if let Err(err) = client
+                    .publish(topic, QoS::AtLeastOnce, true, "online")
+                    .await
+                {
+                    log::warn!("Cannot publish availability: {}", err);
+                }
+                continue;
+            }
+            Update::State(device_id, state) => (
+                device_id,
+                format!("{prefix}/{device_id}/state"),
+                serde_json::to_vec(&state),
+            ),
+            Update::Status(device_id, status) => (
+                device_id,
+                format!("{prefix}/{device_id}/status"),
+                serde_json::to_vec(&status),
+            ),
+        };
+
+        if config.discovery && announced.insert(device_id) {
+            if let Err(err) = announce(&client, &config, &db, device_id).await {
+                log::warn!("Cannot announce device {}: {}", device_id, err);
+            }
+        }
+
+        let res = match payload {
+            Ok(payload) => client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = res {
+            log::warn!("Cannot publish update of device {}: {}", device_id, err);
+        }
+    }
+}
+
+/// Ingests the measurements of the third-party sensors until ctrl-c
+pub async fn subscribe(ingest: Ingest, subscriber: Subscriber) {
+    let Subscriber {
+        sources,
+        mut messages,
+    } = subscriber;
+
+    loop {
+        tokio::select! {
+            Some(msg) = messages.recv() => {
+                let Some(source) = sources.iter().find(|source| source.topic == msg.topic) else {
+                    continue;
+                };
+                let timestamp = utils::ms_since_epoch() as i64;
+                match measurement(source, &msg.payload, timestamp) {
+                    Ok(mes) => ingest.ingest_measurement(db::models::SOURCE_MQTT, mes).await,
+                    Err(err) => ingest.reject(&msg.topic, err),
+                }
+            }
+            Ok(()) = signal::ctrl_c() => { break; }
+        }
+    }
+}
+
+/// Maps the fields of the JSON payload onto the measurement columns,
+/// missing fields are left empty
+fn measurement(
+    source: &config::Source,
+    payload: &[u8],
+    timestamp: i64,
+) -> Result<NewDeviceMeasurement, IngestError> {
+    let json: Value = serde_json::from_slice(payload)
+        .map_err(|err| IngestError::InvalidField("payload", err.to_string()))?;
+
+    let mut mes = NewDeviceMeasurement {
+        device_id: source.device_id as i32,
+        timestamp,
+        ..Default::default()
+    };
+    let mut mapped = false;
+    for (column, field) in &source.fields {
+        let Some(value) = field
+            .path()
+            .split('.')
+            .try_fold(&json, |value, key| value.get(key))
+            .and_then(Value::as_f64)
+        else {
+            continue;
+        };
+        let value = value * field.scale();
+        mapped = true;
+
+        let column = match column {
+            Column::Temperature => &mut mes.temperature,
+            Column::Humidity => &mut mes.humidity,
+            Column::Pressure => &mut mes.pressure,
+            Column::AirQuality => &mut mes.air_quality,
+            Column::BatV => &mut mes.bat_v,
+            Column::BatCap => &mut mes.bat_cap,
+            Column::Iaq => &mut mes.iaq,
+            Column::Co2 => &mut mes.co2,
+            Column::Voc => &mut mes.voc,
+            Column::RawGas => &mut mes.raw_gas,
+            Column::IaqAccuracy => {
+                mes.iaq_accuracy = Some(value.round() as i32);
+                continue;
+            }
+        };
+        *column = Some(value as f32);
+    }
+
+    if !mapped {
+        return Err(IngestError::InvalidField(
+            "payload",
+            "none of the mapped fields is a number".to_owned(),
+        ));
+    }
+    Ok(mes)
+}
+
+/// Publishes the Home Assistant discovery config of the sensors of the device
+async fn announce(
+    client: &AsyncClient,
+    config: &config::Mqtt,
+    db: &db::Pool,
+    device_id: u32,
+) -> anyhow::Result<()> {
+    let name = db
+        .run(move |db| db.device_name(device_id))
+        .await
+        .map(|name| name.name)
+        .unwrap_or_else(|_| format!("Smart Meter {device_id}"));
+    let prefix = &config.topic_prefix;
+    let object_id = format!("smart_meter_{device_id}");
+
+    for (key, sensor_name, device_class, unit) in SENSORS {
+        let mut payload = json!({
+            "name": sensor_name,
+            "unique_id": format!("{object_id}_{key}"),
+            "state_topic": format!("{prefix}/{device_id}/state"),
+            "value_template": format!("{{{{ value_json.{key} }}}}"),
+            "state_class": "measurement",
+            "availability_topic": format!("{prefix}/availability"),
+            "device": {
+                "identifiers": [object_id],
+                "name": name,
+                "model": "Smart Meter",
+            },
+        });
+        if let Some(device_class) = device_class {
+            payload["device_class"] = device_class.into();
+        }
+        if let Some(unit) = unit {
+            payload["unit_of_measurement"] = unit.into();
+        }
+        let topic = format!(
+            "{}/sensor/{object_id}/{key}/config",
+            config.discovery_prefix
+        );
+        client
+            .publish(topic, QoS::AtLeastOnce, true, serde_json::to_vec(&payload)?)
+            .await?;
+    }
+
+    Ok(())
+}
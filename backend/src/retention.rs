@@ -0,0 +1,147 @@
+//! Retention job: deletes the measurements, rollups and journal entries older than the
+//! configured days and shrinks the database file once enough of it is unused,
+//! the ingestion writer is paused meanwhile.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use common::req::RetentionReport;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::{config, db, ingest::WriterLock, utils};
+
+const DAY: i64 = 86_400_000; // ms
+const PRUNE_BATCH: u32 = 10_000; // rows per delete
+const VACUUM_MIN_FREE: i64 = 4; // vacuum once 1/4 of the pages are unused
+
+/// Latest report of the job, shared with the API
+#[derive(Clone, Default)]
+pub struct Reports {
+    latest: Arc<Mutex<Option<RetentionReport>>>,
+}
+
+impl Reports {
+    pub fn latest(&self) -> Option<RetentionReport> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+/// Starts the job if enabled, it runs right away and then every interval
+pub fn start(config: config::Retention, db: db::Pool, writer: WriterLock) -> Reports {
+    let reports = Reports::default();
+    if config.enabled {
+        actix_web::rt::spawn(run(config, db, writer, reports.clone()));
+    }
+    reports
+}
+
+async fn run(config: config::Retention, db: db::Pool, writer: WriterLock, reports: Reports) {
+    let mut interval = time::interval(Duration::from_secs(config.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let started_at = utils::ms_since_epoch() as i64;
+        let (job, writer) = (config.clone(), writer.clone());
+        let res = db
+            .run(move |db| {
+                let mut report = RetentionReport {
+                    started_at,
+                    ..Default::default()
+                };
+                if let Err(err) = apply(&job, db, &writer, &mut report) {
+                    report.error = Some(err.to_string());
+                }
+                anyhow::Ok(report)
+            })
+            .await;
+        let mut report = res.unwrap_or_else(|err| RetentionReport {
+            started_at,
+            error: Some(err.to_string()),
+            ..Default::default()
+        });
+        report.finished_at = utils::ms_since_epoch() as i64;
+        match &report.error {
+            Some(err) => log::error!("Retention failed: {}", err),
+            None => log::info!(
+                "Retention pruned {} measurements, {} hourly and {} daily rollups, \
+                 {} journal entries, freed {} bytes",
+                report.raw,
+                report.hourly,
+                report.daily,
+                report.journal,
+                report.freed_bytes
+            ),
+        }
+        *reports.latest.lock().unwrap() = Some(report);
+    }
+}
+
+/// Days to keep the raw measurements, the hourly and the daily rollups of the device
+fn days(config: &config::Retention, device_id: i32) -> [Option<u32>; 3] {
+    match config
+        .devices
+        .iter()
+        .find(|device| device.device_id as i32 == device_id)
+    {
+        Some(device) => [device.raw_days, device.hourly_days, device.daily_days],
+        None => [config.raw_days, config.hourly_days, config.daily_days],
+    }
+}
+
+fn apply(
+    config: &config::Retention,
+    db: &mut db::Db,
+    writer: &WriterLock,
+    report: &mut RetentionReport,
+) -> Result<()> {
+    let tables = ["measurements", db::ROLLUPS[0].0, db::ROLLUPS[1].0];
+    let mut pruned = [0; 3];
+
+    for device_id in db.device_ids(&tables)? {
+        for (i, days) in days(config, device_id).into_iter().enumerate() {
+            let Some(days) = days else {
+                continue;
+            };
+            let before = report.started_at - days as i64 * DAY;
+            loop {
+                let deleted = db.prune(tables[i], device_id, before, PRUNE_BATCH)?;
+                pruned[i] += deleted as u64;
+                if deleted < PRUNE_BATCH as usize {
+                    break;
+                }
+            }
+        }
+    }
+    [report.raw, report.hourly, report.daily] = pruned;
+
+    if let Some(days) = config.journal_days {
+        let before = report.started_at - days as i64 * DAY;
+        loop {
+            let deleted = db.prune_journal(before, PRUNE_BATCH)?;
+            report.journal += deleted as u64;
+            if deleted < PRUNE_BATCH as usize {
+                break;
+            }
+        }
+    }
+
+    if config.vacuum {
+        let before = db.page_count()?;
+        if before.freelist_count * VACUUM_MIN_FREE >= before.page_count {
+            // needs the database to itself, the packets queue up in the meantime
+            let _paused = writer.pause();
+            db.vacuum()?;
+            let after = db.page_count()?;
+            report.vacuumed = true;
+            report.freed_bytes =
+                ((before.page_count - after.page_count) * before.page_size).max(0) as u64;
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,30 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    commands (id) {
+        id -> Integer,
+        device_id -> Integer,
+        command -> Text,
+        status -> Integer,
+        attempts -> Integer,
+        created_at -> BigInt,
+        updated_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    device_diagnostics (id) {
+        id -> Integer,
+        device_id -> Integer,
+        timestamp -> BigInt,
+        rssi -> Nullable<Integer>,
+        connect_attempts -> Integer,
+        connect_duration -> Integer,
+        free_heap -> BigInt,
+        reset_reason -> Integer,
+    }
+}
+
 diesel::table! {
     device_names (device_id) {
         device_id -> Integer,
@@ -17,25 +42,46 @@ diesel::table! {
         report_interval -> Integer,
         sample_interval -> Integer,
         last_seen -> BigInt,
+        auth_key -> Nullable<Binary>,
+        source -> Text,
+        last_nonce -> Nullable<Binary>,
     }
 }
 
 diesel::table! {
-    measurements (device_id, timestamp) {
+    events (id) {
+        id -> Integer,
         device_id -> Integer,
         timestamp -> BigInt,
-        temperature -> Nullable<Float>,
-        humidity -> Nullable<Float>,
-        pressure -> Nullable<Float>,
-        air_quality -> Nullable<Float>,
-        bat_v -> Nullable<Float>,
-        bat_cap -> Nullable<Float>,
+        kind -> Text,
+        event -> Text,
     }
 }
 
 diesel::table! {
-    measurements_old (id) {
+    journal (id) {
         id -> Integer,
+        received_at -> BigInt,
+        source -> Text,
+        device_id -> Integer,
+        data -> Binary,
+    }
+}
+
+diesel::table! {
+    link_stats (device_id) {
+        device_id -> Integer,
+        boot_id -> BigInt,
+        last_sequence -> BigInt,
+        seen_window -> BigInt,
+        received -> BigInt,
+        missing -> BigInt,
+        duplicates -> BigInt,
+    }
+}
+
+diesel::table! {
+    measurements (device_id, timestamp) {
         device_id -> Integer,
         timestamp -> BigInt,
         temperature -> Nullable<Float>,
@@ -44,12 +90,49 @@ diesel::table! {
         air_quality -> Nullable<Float>,
         bat_v -> Nullable<Float>,
         bat_cap -> Nullable<Float>,
+        iaq -> Nullable<Float>,
+        iaq_accuracy -> Nullable<Integer>,
+        co2 -> Nullable<Float>,
+        voc -> Nullable<Float>,
+        raw_gas -> Nullable<Float>,
+        stabilization_status -> Nullable<Bool>,
+        run_in_status -> Nullable<Bool>,
+    }
+}
+
+diesel::table! {
+    measurements_daily (device_id, timestamp, field) {
+        device_id -> Integer,
+        timestamp -> BigInt,
+        field -> Text,
+        count -> Integer,
+        min -> Double,
+        max -> Double,
+        sum -> Double,
+    }
+}
+
+diesel::table! {
+    measurements_hourly (device_id, timestamp, field) {
+        device_id -> Integer,
+        timestamp -> BigInt,
+        field -> Text,
+        count -> Integer,
+        min -> Double,
+        max -> Double,
+        sum -> Double,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
+    commands,
+    device_diagnostics,
     device_names,
     devices,
+    events,
+    journal,
+    link_stats,
     measurements,
-    measurements_old,
+    measurements_daily,
+    measurements_hourly,
 );
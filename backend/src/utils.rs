@@ -1,4 +1,5 @@
-use chrono::{DateTime, Utc};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate};
 
 pub fn ms_since_epoch() -> u128 {
     std::time::SystemTime::now()
@@ -7,8 +8,116 @@ pub fn ms_since_epoch() -> u128 {
         .as_millis()
 }
 
-pub fn utc_with_offset(offset_ms: i64) -> DateTime<Utc> {
-    let now = Utc::now();
-    now.checked_add_signed(chrono::Duration::milliseconds(offset_ms))
+/// Parses an RFC 3339 date or a plain date (midnight UTC) into ms since epoch
+pub fn parse_date(s: &str) -> Result<i64> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.timestamp_millis());
+    }
+
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    Ok(date
+        .and_hms_opt(0, 0, 0)
         .unwrap()
+        .and_utc()
+        .timestamp_millis())
+}
+
+/// Largest triangle three buckets: indices of at most `threshold` points (ordered by time)
+/// that keep the visual shape of the series, always including the first and the last
+pub fn lttb(points: &[(i64, f32)], threshold: usize) -> Vec<usize> {
+    let len = points.len();
+    if threshold >= len {
+        return (0..len).collect();
+    }
+    match threshold {
+        0 => return vec![],
+        1 => return vec![len - 1],
+        2 => return vec![0, len - 1],
+        _ => (),
+    }
+
+    let point = |i: usize| (points[i].0 as f64, points[i].1 as f64);
+    // the points between the first and the last are split into equally sized buckets
+    let every = (len - 2) as f64 / (threshold - 2) as f64;
+    let mut selected = Vec::with_capacity(threshold);
+    selected.push(0);
+    let mut a = 0;
+
+    for bucket in 0..threshold - 2 {
+        // average of the next bucket, the last point for the last bucket
+        let next_start = ((bucket + 1) as f64 * every) as usize + 1;
+        let next_end = (((bucket + 2) as f64 * every) as usize + 1).min(len);
+        let next = next_start..next_end;
+        let count = next.len() as f64;
+        let (avg_x, avg_y) = next.map(point).fold((0.0, 0.0), |(x, y), (px, py)| {
+            (x + px / count, y + py / count)
+        });
+
+        // the point forming the largest triangle with the previous one and the average
+        let (ax, ay) = point(a);
+        let start = (bucket as f64 * every) as usize + 1;
+        let end = ((bucket + 1) as f64 * every) as usize + 1;
+        a = (start..end)
+            .max_by(|&i, &j| {
+                let area = |i| {
+                    let (bx, by) = point(i);
+                    ((ax - avg_x) * (by - ay) - (ax - bx) * (avg_y - ay)).abs()
+                };
+                area(i).total_cmp(&area(j))
+            })
+            .unwrap_or(start);
+        selected.push(a);
+    }
+
+    selected.push(len - 1);
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(len: usize) -> Vec<(i64, f32)> {
+        (0..len)
+            .map(|i| (i as i64 * 1000, ((i * 7) % 13) as f32))
+            .collect()
+    }
+
+    #[test]
+    fn lttb_keeps_all_points_below_threshold() {
+        let points = series(10);
+        assert_eq!(lttb(&points, 10), (0..10).collect::<Vec<_>>());
+        assert_eq!(lttb(&points, 100), (0..10).collect::<Vec<_>>());
+        assert!(lttb(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn lttb_small_thresholds() {
+        let points = series(10);
+        assert!(lttb(&points, 0).is_empty());
+        assert_eq!(lttb(&points, 1), vec![9]);
+        assert_eq!(lttb(&points, 2), vec![0, 9]);
+    }
+
+    #[test]
+    fn lttb_keeps_first_and_last() {
+        let points = series(1000);
+        for threshold in [3, 10, 99, 500, 999] {
+            let selected = lttb(&points, threshold);
+            assert_eq!(selected.len(), threshold);
+            assert_eq!(selected.first(), Some(&0));
+            assert_eq!(selected.last(), Some(&999));
+            assert!(selected.windows(2).all(|w| w[0] < w[1]), "{selected:?}");
+        }
+    }
+
+    #[test]
+    fn lttb_keeps_peaks() {
+        let mut points: Vec<_> = (0..100).map(|i| (i as i64, 0.0)).collect();
+        points[42].1 = 10.0;
+        points[77].1 = -10.0;
+        let selected = lttb(&points, 10);
+        assert!(selected.contains(&42));
+        assert!(selected.contains(&77));
+    }
 }
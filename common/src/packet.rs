@@ -1,9 +1,29 @@
-const MAGIC: &str = "BRST";
+pub mod auth;
+pub mod command;
+mod compact;
+pub mod crypto;
+mod legacy;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub use compact::CompactMeasurement;
+
+pub const MAGIC: [u8; 4] = *b"BRST";
+
+/// Largest datagram that is guaranteed not to be fragmented
+pub const MAX_DATAGRAM_SIZE: usize = 508;
+
+/// Version of the wire format, bump on any layout change of `Header` or `Payload`
+pub const PROTOCOL_VERSION: u8 = 4;
+
+/// Starts with the magic and the version byte at fixed offsets,
+/// postcard encodes the integers after them as varints
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Header {
-    magic: String,
+    magic: [u8; 4],
+    version: u8,
+    pub flags: u8, // capabilities, see `flags`
     pub device_id: u32,
+    pub boot_id: u32,       // changes on every boot
+    pub sequence: u32,      // per boot, incremented for every packet
     pub timestamp: u64,     // ms since boot
     pub rel_timestamp: i64, // ms since packet was sent
 }
@@ -12,12 +32,63 @@ pub struct Header {
 impl Header {
     pub fn new(device_id: u32, timestamp: u64) -> Self {
         Self {
-            magic: MAGIC.to_string(),
+            magic: MAGIC,
+            version: PROTOCOL_VERSION,
+            flags: 0,
             device_id,
+            boot_id: 0,
+            sequence: 0,
             timestamp,
             rel_timestamp: 0,
         }
     }
+
+    /// Protocol version the packet was encoded with
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// `boot_id` and `sequence` are only sent since protocol version 2
+    pub fn has_sequence(&self) -> bool {
+        self.version >= 2
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag > 0
+    }
+
+    pub fn set_flag(&mut self, flag: u8, active: bool) {
+        if active {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+}
+
+/// Bits of `Header::flags`
+pub mod flags {
+    pub const AUTHENTICATED: u8 = 1 << 0; // HMAC tag appended to the packet
+    pub const ENCRYPTED: u8 = 1 << 1; // payload encrypted, nonce prepended and tag appended
+}
+
+/// How a packet is protected on the wire
+#[derive(Debug, Clone, Copy)]
+pub enum Protection<'a> {
+    Plain,
+    Authenticated(&'a auth::Key),
+    Encrypted(&'a auth::Key, crypto::Nonce),
+}
+
+impl Protection<'_> {
+    /// Bytes added to the encoded packet
+    pub fn overhead(&self) -> usize {
+        match self {
+            Protection::Plain => 0,
+            Protection::Authenticated(_) => auth::TAG_LEN,
+            Protection::Encrypted(..) => crypto::NONCE_LEN + crypto::TAG_LEN,
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -26,21 +97,294 @@ pub struct Packet {
     pub payload: Payload,
 }
 
+impl Packet {
+    /// Encodes the packet into `buf`, protected by a device key
+    pub fn encode<'a>(
+        &self,
+        buf: &'a mut [u8],
+        protection: Protection,
+    ) -> Result<&'a mut [u8], postcard::Error> {
+        let mut header = self.header.clone();
+        header.set_flag(
+            flags::AUTHENTICATED,
+            matches!(protection, Protection::Authenticated(_)),
+        );
+        header.set_flag(
+            flags::ENCRYPTED,
+            matches!(protection, Protection::Encrypted(..)),
+        );
+
+        let header_len = postcard::to_slice(&header, buf)?.len();
+        let mut len = header_len;
+
+        match protection {
+            Protection::Plain => {
+                len += postcard::to_slice(&self.payload, &mut buf[len..])?.len();
+            }
+            Protection::Authenticated(key) => {
+                len += postcard::to_slice(&self.payload, &mut buf[len..])?.len();
+                let tag = auth::tag(key, &buf[..len]);
+                len += put(&mut buf[len..], &tag)?;
+            }
+            Protection::Encrypted(key, nonce) => {
+                len += put(&mut buf[len..], &nonce.to_bytes())?;
+                let payload_len = postcard::to_slice(&self.payload, &mut buf[len..])?.len();
+
+                let (aad, data) = buf.split_at_mut(header_len);
+                let tag = crypto::encrypt(
+                    key,
+                    nonce,
+                    aad,
+                    &mut data[crypto::NONCE_LEN..crypto::NONCE_LEN + payload_len],
+                );
+                len += payload_len;
+                len += put(&mut buf[len..], &tag)?;
+            }
+        }
+
+        Ok(&mut buf[..len])
+    }
+
+    /// Decodes an unencrypted packet of any supported protocol version,
+    /// the authentication tag (if any) is not checked
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        Frame::parse(buf)?.packet()
+    }
+}
+
+fn put(buf: &mut [u8], bytes: &[u8]) -> Result<usize, postcard::Error> {
+    buf.get_mut(..bytes.len())
+        .ok_or(postcard::Error::SerializeBufferFull)?
+        .copy_from_slice(bytes);
+    Ok(bytes.len())
+}
+
+/// A received datagram split into its header, encoded payload and authentication tag
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub header: Header,
+    body: &'a [u8],    // header + payload, covered by the tag
+    payload: &'a [u8], // encoded payload, nonce + ciphertext + tag if encrypted
+    tag: Option<&'a [u8]>,
+}
+
+impl<'a> Frame<'a> {
+    /// Parses the header of any supported protocol version,
+    /// including the pre-versioned layout with a string magic
+    pub fn parse(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let (header, rest) = if legacy::is_v0(buf) {
+            let (header, rest) = postcard::take_from_bytes::<legacy::HeaderV0>(buf)?;
+            (header.into(), rest)
+        } else if buf.len() > MAGIC.len() && buf[..MAGIC.len()] == MAGIC {
+            match buf[MAGIC.len()] {
+                1 => {
+                    let (header, rest) = postcard::take_from_bytes::<legacy::HeaderV1>(buf)?;
+                    (header.into(), rest)
+                }
+                2..=PROTOCOL_VERSION => postcard::take_from_bytes::<Header>(buf)?,
+                version => return Err(DecodeError::UnsupportedVersion(version)),
+            }
+        } else {
+            return Err(DecodeError::InvalidMagic);
+        };
+
+        let payload_start = buf.len() - rest.len();
+        let (body, tag) = if header.has_flag(flags::AUTHENTICATED) {
+            if rest.len() < auth::TAG_LEN {
+                return Err(DecodeError::MissingTag);
+            }
+            let (body, tag) = buf.split_at(buf.len() - auth::TAG_LEN);
+            (body, Some(tag))
+        } else {
+            (buf, None)
+        };
+
+        let payload = &body[payload_start..];
+        if header.has_flag(flags::ENCRYPTED) && payload.len() < crypto::NONCE_LEN + crypto::TAG_LEN
+        {
+            return Err(DecodeError::MissingTag);
+        }
+
+        Ok(Self {
+            header,
+            body,
+            payload,
+            tag,
+        })
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.tag.is_some()
+    }
+
+    /// Checks the authentication tag against the device key, fails if there is no tag
+    pub fn verify(&self, key: &auth::Key) -> bool {
+        self.tag
+            .is_some_and(|tag| auth::verify(key, self.body, tag))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.header.has_flag(flags::ENCRYPTED)
+    }
+
+    pub fn nonce(&self) -> Option<crypto::Nonce> {
+        self.is_encrypted().then(|| {
+            crypto::Nonce::from_bytes(self.payload[..crypto::NONCE_LEN].try_into().unwrap())
+        })
+    }
+
+    pub fn packet(&self) -> Result<Packet, DecodeError> {
+        if self.is_encrypted() {
+            return Err(DecodeError::Encrypted);
+        }
+
+        Ok(Packet {
+            header: self.header.clone(),
+            payload: decode_payload(self.header.version(), self.payload)?,
+        })
+    }
+
+    /// Decrypts and decodes the payload, also authenticates the header
+    pub fn decrypt(&self, key: &auth::Key) -> Result<Packet, DecodeError> {
+        let Some(nonce) = self.nonce() else {
+            return self.packet();
+        };
+
+        let aad = &self.body[..self.body.len() - self.payload.len()];
+        let (data, tag) = self.payload[crypto::NONCE_LEN..]
+            .split_at(self.payload.len() - crypto::NONCE_LEN - crypto::TAG_LEN);
+        let mut data = data.to_vec();
+        crypto::decrypt(key, nonce, aad, &mut data, tag.try_into().unwrap())
+            .map_err(|_| DecodeError::DecryptionFailed)?;
+
+        Ok(Packet {
+            header: self.header.clone(),
+            payload: decode_payload(self.header.version(), &data)?,
+        })
+    }
+}
+
+fn decode_payload(version: u8, buf: &[u8]) -> Result<Payload, postcard::Error> {
+    match version {
+        0..=2 => postcard::from_bytes::<legacy::PayloadV2>(buf).map(Into::into),
+        3 => postcard::from_bytes::<legacy::PayloadV3>(buf).map(Into::into),
+        _ => postcard::from_bytes(buf),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    MissingTag,
+    Encrypted,
+    DecryptionFailed,
+    Malformed(postcard::Error),
+}
+
+impl From<postcard::Error> for DecodeError {
+    fn from(err: postcard::Error) -> Self {
+        Self::Malformed(err)
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "invalid magic"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            Self::MissingTag => write!(f, "missing authentication tag"),
+            Self::Encrypted => write!(f, "payload is encrypted"),
+            Self::DecryptionFailed => write!(f, "decryption failed"),
+            Self::Malformed(err) => write!(f, "malformed packet: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Variants are only ever appended, the index is part of the wire format
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum Payload {
     Measurement(Measurement),
     DeviceInfo(DeviceInfo),
+    MeasurementBatch(MeasurementBatch),
+    CompactMeasurement(CompactMeasurement),
+    Event(Event),
+    Command(command::CommandRequest),
+    CommandAck(command::CommandAck),
+    Ack(Ack),
 }
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone)]
+/// Sent by the backend, the device drops queued packets up to `sequence` of the boot
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Ack {
+    pub boot_id: u32,
+    pub sequence: u32, // all packets up to this one were received
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
 #[allow(unused)]
 pub struct Measurement {
     pub temperature: Option<f32>,  // °C
     pub pressure: Option<f32>,     // Pa
     pub humidity: Option<f32>,     // percent
-    pub air_quality: Option<f32>,  // ohm
+    pub air_quality: Option<f32>,  // static IAQ index, 0..500
     pub bat_voltage: Option<f32>,  // V
     pub bat_capacity: Option<f32>, // percent
+
+    // since protocol version 3
+    pub iaq: Option<f32>,                   // index, 0..500
+    pub iaq_accuracy: Option<u8>, // 0 (unreliable) .. 3 (high), also applies to `air_quality`
+    pub co2: Option<f32>,         // ppm, CO2 equivalent
+    pub voc: Option<f32>,         // ppm, breath VOC equivalent
+    pub raw_gas: Option<f32>,     // ohm
+    pub stabilization_status: Option<bool>, // gas sensor stabilized
+    pub run_in_status: Option<bool>, // gas sensor run-in finished
+}
+
+/// Samples sharing one header, `Header::timestamp` is the time of the first sample
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone)]
+pub struct MeasurementBatch {
+    pub samples: Vec<BatchSample>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone)]
+pub struct BatchSample {
+    pub offset: u32, // ms since `Header::timestamp`
+    pub measurement: Measurement,
+}
+
+impl Packet {
+    /// Appends a sample to a batch packet if it still fits into a single datagram
+    /// after adding `overhead` bytes, see `Protection::overhead`
+    pub fn try_push_sample(
+        &mut self,
+        timestamp: u64,
+        measurement: &Measurement,
+        overhead: usize,
+    ) -> bool {
+        let Payload::MeasurementBatch(batch) = &mut self.payload else {
+            return false;
+        };
+        let Ok(offset) = u32::try_from(timestamp.saturating_sub(self.header.timestamp)) else {
+            return false;
+        };
+
+        batch.samples.push(BatchSample {
+            offset,
+            measurement: measurement.clone(),
+        });
+
+        let fits = postcard::experimental::serialized_size(&*self)
+            .is_ok_and(|size| size + overhead <= MAX_DATAGRAM_SIZE);
+        if !fits {
+            if let Payload::MeasurementBatch(batch) = &mut self.payload {
+                batch.samples.pop();
+            }
+        }
+        fits
+    }
 }
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize, Clone)]
@@ -53,4 +397,61 @@ pub struct DeviceInfo {
     pub bsec_version: [u8; 4],       // major.minor.bugfix.misc
     pub model: [u8; 16],             // utf8 string
     pub wifi_ssid: Option<[u8; 32]>, // utf8 string (last connected wifi)
+
+    // since protocol version 4
+    pub diagnostics: Option<Diagnostics>,
+}
+
+/// Health of the device and its last WiFi connection
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Diagnostics {
+    pub rssi: Option<i8>,      // dBm
+    pub connect_attempts: u8,  // until connected or given up
+    pub connect_duration: u32, // ms
+    pub free_heap: u32,        // bytes
+    pub reset_reason: ResetReason,
+}
+
+/// Noteworthy things happening on the device, variants are only ever appended
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Event {
+    ButtonPressed,
+    LowBattery { voltage: f32, capacity: f32 }, // V, percent
+    SensorBusy,
+    SensorInvalid,
+    WifiFailure { attempts: u8 },
+    Boot { reset_reason: ResetReason },
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::ButtonPressed => "button_pressed",
+            Event::LowBattery { .. } => "low_battery",
+            Event::SensorBusy => "sensor_busy",
+            Event::SensorInvalid => "sensor_invalid",
+            Event::WifiFailure { .. } => "wifi_failure",
+            Event::Boot { .. } => "boot",
+        }
+    }
+}
+
+/// Mirrors `esp_reset_reason_t`
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, num_enum::FromPrimitive,
+)]
+#[repr(u8)]
+pub enum ResetReason {
+    #[num_enum(default)]
+    Unknown = 0,
+    PowerOn = 1,
+    External = 2,
+    Software = 3,
+    Panic = 4,
+    InterruptWatchdog = 5,
+    TaskWatchdog = 6,
+    Watchdog = 7,
+    DeepSleep = 8,
+    Brownout = 9,
+    Sdio = 10,
 }
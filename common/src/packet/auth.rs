@@ -0,0 +1,129 @@
+//! Packet authentication with per-device keys (truncated HMAC-SHA256).
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const KEY_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+pub type Key = [u8; KEY_LEN];
+
+fn mac(key: &Key, data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac
+}
+
+pub fn tag(key: &Key, data: &[u8]) -> [u8; TAG_LEN] {
+    let mut tag = [0; TAG_LEN];
+    tag.copy_from_slice(&mac(key, data).finalize().into_bytes()[..TAG_LEN]);
+    tag
+}
+
+/// Derives a key for another purpose from the device key
+pub fn derive_key(key: &Key, context: &[u8]) -> Key {
+    mac(key, context).finalize().into_bytes().into()
+}
+
+/// constant time comparison
+pub fn verify(key: &Key, data: &[u8], tag: &[u8]) -> bool {
+    tag.len() == TAG_LEN && mac(key, data).verify_truncated_left(tag).is_ok()
+}
+
+pub fn key_from_hex(s: &str) -> Option<Key> {
+    from_hex(s)?.try_into().ok()
+}
+
+/// Decodes hex digits of any even length, e.g. a tag
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn key_to_hex(key: &Key) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{
+        DecodeError, Frame, Header, Measurement, Packet, Payload, Protection, MAX_DATAGRAM_SIZE,
+    };
+
+    const KEY: Key = [7; KEY_LEN];
+
+    fn encode(key: &Key) -> Vec<u8> {
+        let packet = Packet {
+            header: Header::new(42, 1000),
+            payload: Payload::Measurement(Measurement {
+                temperature: Some(21.5),
+                ..Default::default()
+            }),
+        };
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        packet
+            .encode(&mut buf, Protection::Authenticated(key))
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytes = encode(&KEY);
+        let frame = Frame::parse(&bytes).unwrap();
+        assert!(frame.is_authenticated());
+        assert!(frame.verify(&KEY));
+        let Payload::Measurement(mes) = frame.packet().unwrap().payload else {
+            panic!("not a measurement");
+        };
+        assert_eq!(mes.temperature, Some(21.5));
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let bytes = encode(&KEY);
+        assert!(!Frame::parse(&bytes).unwrap().verify(&[8; KEY_LEN]));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let bytes = encode(&KEY);
+        // every byte is covered, the header as well as the payload and the tag itself
+        for i in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 0x01;
+            let verified = Frame::parse(&tampered).is_ok_and(|frame| frame.verify(&KEY));
+            assert!(!verified, "byte {i} not covered");
+        }
+    }
+
+    #[test]
+    fn rejects_missing_tag() {
+        let mut header = Header::new(42, 1000);
+        header.set_flag(crate::packet::flags::AUTHENTICATED, true);
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let header_len = postcard::to_slice(&header, &mut buf).unwrap().len();
+        let bytes = encode(&KEY);
+        assert_eq!(
+            Frame::parse(&bytes[..header_len + TAG_LEN - 1]).unwrap_err(),
+            DecodeError::MissingTag
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(key_from_hex(&key_to_hex(&KEY)), Some(KEY));
+        assert_eq!(from_hex(" 0aff "), Some(vec![0x0a, 0xff]));
+        assert_eq!(from_hex("0af"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(key_from_hex("0aff"), None);
+    }
+}
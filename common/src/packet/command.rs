@@ -0,0 +1,36 @@
+//! Commands sent by the backend to a device while it listens after its report.
+//!
+//! Commands are sent as `Payload::Command` to `COMMAND_PORT`, authenticated with
+//! the device key if one is provisioned, and answered with `Payload::CommandAck`.
+//! Delivery acknowledgments (`Payload::Ack`) use the same port.
+
+pub const COMMAND_PORT: u16 = 6464;
+
+/// Variants are only ever appended, the index is part of the wire format
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Command {
+    SetSampleRate(SampleRate),
+    SetReportDivider(u32),      // report every n-th sample
+    Identify { duration: u16 }, // s, blinks the LED
+    Reboot,
+    RequestDeviceInfo,
+}
+
+/// Sample rates supported by BSEC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SampleRate {
+    Ulp, // 5min
+    Lp,  // 3s
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CommandRequest {
+    pub id: u32, // only ever increases, older ids are ignored by the device
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CommandAck {
+    pub id: u32,
+    pub accepted: bool,
+}
@@ -0,0 +1,299 @@
+//! Fixed-point wire representation of the basic `Measurement` fields.
+//!
+//! A presence bitmap is followed by the present fields only,
+//! postcard stores the integers as varints. The BSEC outputs added with protocol
+//! version 3 (`iaq`, `co2`, ...) are not part of it, devices reporting them send
+//! a `Measurement` instead.
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::Measurement;
+
+const TEMPERATURE: u8 = 1 << 0;
+const PRESSURE: u8 = 1 << 1;
+const HUMIDITY: u8 = 1 << 2;
+const AIR_QUALITY: u8 = 1 << 3;
+const BAT_VOLTAGE: u8 = 1 << 4;
+const BAT_CAPACITY: u8 = 1 << 5;
+
+const FIELD_COUNT: usize = 6;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactMeasurement {
+    present: u8,
+    temperature: i16,  // 0.01 °C
+    pressure: u32,     // Pa
+    humidity: u16,     // 0.01 percent
+    air_quality: u16,  // 0.01 IAQ
+    bat_voltage: u16,  // mV
+    bat_capacity: u16, // 0.01 percent
+}
+
+impl CompactMeasurement {
+    fn get(&self, field: u8, value: f32) -> Option<f32> {
+        (self.present & field > 0).then_some(value)
+    }
+
+    /// Rounds to the fixed-point resolution, out of range values saturate
+    fn set<T>(&mut self, field: u8, value: Option<f32>, scale: f32, to_fixed: fn(f32) -> T) -> T
+    where
+        T: Default,
+    {
+        match value.filter(|v| v.is_finite()) {
+            Some(v) => {
+                self.present |= field;
+                to_fixed((v * scale).round())
+            }
+            None => T::default(),
+        }
+    }
+}
+
+/// Drops the BSEC outputs, see the module docs
+impl From<&Measurement> for CompactMeasurement {
+    fn from(mes: &Measurement) -> Self {
+        let mut c = Self::default();
+        c.temperature = c.set(TEMPERATURE, mes.temperature, 100.0, |v| v as i16);
+        c.pressure = c.set(PRESSURE, mes.pressure, 1.0, |v| v as u32);
+        c.humidity = c.set(HUMIDITY, mes.humidity, 100.0, |v| v as u16);
+        c.air_quality = c.set(AIR_QUALITY, mes.air_quality, 100.0, |v| v as u16);
+        c.bat_voltage = c.set(BAT_VOLTAGE, mes.bat_voltage, 1000.0, |v| v as u16);
+        c.bat_capacity = c.set(BAT_CAPACITY, mes.bat_capacity, 100.0, |v| v as u16);
+        c
+    }
+}
+
+impl From<&CompactMeasurement> for Measurement {
+    fn from(c: &CompactMeasurement) -> Self {
+        Self {
+            temperature: c.get(TEMPERATURE, c.temperature as f32 / 100.0),
+            pressure: c.get(PRESSURE, c.pressure as f32),
+            humidity: c.get(HUMIDITY, c.humidity as f32 / 100.0),
+            air_quality: c.get(AIR_QUALITY, c.air_quality as f32 / 100.0),
+            bat_voltage: c.get(BAT_VOLTAGE, c.bat_voltage as f32 / 1000.0),
+            bat_capacity: c.get(BAT_CAPACITY, c.bat_capacity as f32 / 100.0),
+            ..Default::default()
+        }
+    }
+}
+
+impl Serialize for CompactMeasurement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(1 + self.present.count_ones() as usize)?;
+        tup.serialize_element(&self.present)?;
+        if self.present & TEMPERATURE > 0 {
+            tup.serialize_element(&self.temperature)?;
+        }
+        if self.present & PRESSURE > 0 {
+            tup.serialize_element(&self.pressure)?;
+        }
+        if self.present & HUMIDITY > 0 {
+            tup.serialize_element(&self.humidity)?;
+        }
+        if self.present & AIR_QUALITY > 0 {
+            tup.serialize_element(&self.air_quality)?;
+        }
+        if self.present & BAT_VOLTAGE > 0 {
+            tup.serialize_element(&self.bat_voltage)?;
+        }
+        if self.present & BAT_CAPACITY > 0 {
+            tup.serialize_element(&self.bat_capacity)?;
+        }
+        tup.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CompactMeasurement {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(1 + FIELD_COUNT, CompactVisitor)
+    }
+}
+
+struct CompactVisitor;
+
+impl<'de> Visitor<'de> for CompactVisitor {
+    type Value = CompactMeasurement;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a presence bitmap followed by the present fields")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        fn next<'de, A, T>(seq: &mut A, present: u8, field: u8) -> Result<T, A::Error>
+        where
+            A: SeqAccess<'de>,
+            T: Deserialize<'de> + Default,
+        {
+            if present & field == 0 {
+                return Ok(T::default());
+            }
+            seq.next_element()?
+                .ok_or_else(|| de::Error::custom("missing field"))
+        }
+
+        let present: u8 = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::custom("missing presence bitmap"))?;
+
+        Ok(CompactMeasurement {
+            present,
+            temperature: next(&mut seq, present, TEMPERATURE)?,
+            pressure: next(&mut seq, present, PRESSURE)?,
+            humidity: next(&mut seq, present, HUMIDITY)?,
+            air_quality: next(&mut seq, present, AIR_QUALITY)?,
+            bat_voltage: next(&mut seq, present, BAT_VOLTAGE)?,
+            bat_capacity: next(&mut seq, present, BAT_CAPACITY)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Header, Packet, Payload, Protection, MAX_DATAGRAM_SIZE};
+
+    fn full() -> Measurement {
+        Measurement {
+            temperature: Some(21.37),
+            pressure: Some(101325.0),
+            humidity: Some(45.12),
+            air_quality: Some(87.5),
+            bat_voltage: Some(5.213),
+            bat_capacity: Some(78.25),
+            ..Default::default()
+        }
+    }
+
+    fn partial() -> Measurement {
+        Measurement {
+            temperature: Some(-12.5),
+            humidity: Some(99.99),
+            ..Default::default()
+        }
+    }
+
+    fn encoded_len<T: Serialize>(value: &T) -> usize {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        postcard::to_slice(value, &mut buf).unwrap().len()
+    }
+
+    fn wire_roundtrip(c: &CompactMeasurement) -> CompactMeasurement {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let bytes = postcard::to_slice(c, &mut buf).unwrap();
+        postcard::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn roundtrip_full() {
+        let mes = full();
+        let c = CompactMeasurement::from(&mes);
+        assert_eq!(Measurement::from(&wire_roundtrip(&c)), mes);
+    }
+
+    #[test]
+    fn roundtrip_partial() {
+        let mes = partial();
+        let c = CompactMeasurement::from(&mes);
+        assert_eq!(Measurement::from(&wire_roundtrip(&c)), mes);
+    }
+
+    #[test]
+    fn roundtrip_empty() {
+        let mes = Measurement::default();
+        let c = CompactMeasurement::from(&mes);
+        assert_eq!(Measurement::from(&wire_roundtrip(&c)), mes);
+    }
+
+    #[test]
+    fn roundtrip_compact() {
+        let c = CompactMeasurement::from(&full());
+        assert_eq!(CompactMeasurement::from(&Measurement::from(&c)), c);
+    }
+
+    #[test]
+    fn rounds_to_resolution() {
+        let c = CompactMeasurement::from(&Measurement {
+            temperature: Some(21.374),
+            pressure: Some(101325.4),
+            ..Default::default()
+        });
+        let mes = Measurement::from(&c);
+        assert_eq!(mes.temperature, Some(21.37));
+        assert_eq!(mes.pressure, Some(101325.0));
+    }
+
+    #[test]
+    fn saturates_out_of_range() {
+        let c = CompactMeasurement::from(&Measurement {
+            temperature: Some(1000.0),
+            bat_voltage: Some(-1.0),
+            ..Default::default()
+        });
+        let mes = Measurement::from(&c);
+        assert_eq!(mes.temperature, Some(327.67));
+        assert_eq!(mes.bat_voltage, Some(0.0));
+    }
+
+    #[test]
+    fn non_finite_is_absent() {
+        let c = CompactMeasurement::from(&Measurement {
+            temperature: Some(f32::NAN),
+            humidity: Some(f32::INFINITY),
+            ..Default::default()
+        });
+        assert_eq!(Measurement::from(&c), Measurement::default());
+    }
+
+    #[test]
+    fn smaller_than_measurement() {
+        for mes in [full(), partial(), Measurement::default()] {
+            let compact = encoded_len(&CompactMeasurement::from(&mes));
+            let plain = encoded_len(&mes);
+            assert!(compact < plain, "{compact} >= {plain} bytes for {mes:?}");
+        }
+    }
+
+    #[test]
+    fn drops_bsec_outputs() {
+        let mes = Measurement {
+            iaq: Some(42.0),
+            iaq_accuracy: Some(3),
+            co2: Some(600.0),
+            voc: Some(0.5),
+            raw_gas: Some(120_000.0),
+            stabilization_status: Some(true),
+            run_in_status: Some(true),
+            ..full()
+        };
+        let c = CompactMeasurement::from(&mes);
+        assert_eq!(c, CompactMeasurement::from(&full()));
+        assert_eq!(Measurement::from(&wire_roundtrip(&c)), full());
+    }
+
+    #[test]
+    fn encoded_sizes() {
+        assert_eq!(encoded_len(&full()), 37);
+        assert_eq!(encoded_len(&CompactMeasurement::from(&full())), 14);
+        assert_eq!(encoded_len(&CompactMeasurement::from(&partial())), 5);
+        assert_eq!(encoded_len(&CompactMeasurement::default()), 1);
+    }
+
+    #[test]
+    fn roundtrip_packet() {
+        let pkt = Packet {
+            header: Header::new(42, 1000),
+            payload: Payload::CompactMeasurement(CompactMeasurement::from(&full())),
+        };
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let bytes = pkt.encode(&mut buf, Protection::Plain).unwrap();
+
+        match Packet::decode(bytes).unwrap().payload {
+            Payload::CompactMeasurement(c) => assert_eq!(Measurement::from(&c), full()),
+            payload => panic!("unexpected payload {payload:?}"),
+        }
+    }
+}
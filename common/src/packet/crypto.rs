@@ -0,0 +1,159 @@
+//! Payload encryption (ChaCha20-Poly1305) with per-device keys.
+//!
+//! The nonce is made up of a persistent epoch (incremented on every boot)
+//! and a counter incremented for every packet, it is sent along in the clear.
+
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit};
+
+use super::auth;
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Nonce {
+    pub epoch: u32,
+    pub counter: u64,
+}
+
+impl Nonce {
+    pub fn new(epoch: u32) -> Self {
+        Self { epoch, counter: 0 }
+    }
+
+    /// Returns the current nonce and advances the counter
+    pub fn advance(&mut self) -> Self {
+        let nonce = *self;
+        self.counter += 1;
+        nonce
+    }
+
+    pub fn to_bytes(self) -> [u8; NONCE_LEN] {
+        let mut bytes = [0; NONCE_LEN];
+        bytes[..4].copy_from_slice(&self.epoch.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.counter.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; NONCE_LEN]) -> Self {
+        Self {
+            epoch: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            counter: u64::from_le_bytes(bytes[4..].try_into().unwrap()),
+        }
+    }
+}
+
+/// the device key is also used for HMAC tags, derive a separate one for encryption
+fn cipher(key: &auth::Key) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&auth::derive_key(key, b"smart-meter payload encryption").into())
+}
+
+pub fn encrypt(key: &auth::Key, nonce: Nonce, aad: &[u8], data: &mut [u8]) -> [u8; TAG_LEN] {
+    cipher(key)
+        .encrypt_in_place_detached(&nonce.to_bytes().into(), aad, data)
+        .expect("payload exceeds cipher limits")
+        .into()
+}
+
+pub fn decrypt(
+    key: &auth::Key,
+    nonce: Nonce,
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8; TAG_LEN],
+) -> Result<(), chacha20poly1305::Error> {
+    cipher(key).decrypt_in_place_detached(&nonce.to_bytes().into(), aad, data, tag.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{
+        DecodeError, Frame, Header, Measurement, Packet, Payload, Protection, MAX_DATAGRAM_SIZE,
+    };
+
+    const KEY: auth::Key = [7; auth::KEY_LEN];
+    const NONCE: Nonce = Nonce {
+        epoch: 3,
+        counter: 9,
+    };
+
+    fn measurement() -> Measurement {
+        Measurement {
+            temperature: Some(21.5),
+            co2: Some(600.0),
+            ..Default::default()
+        }
+    }
+
+    fn encode(key: &auth::Key, nonce: Nonce) -> Vec<u8> {
+        let packet = Packet {
+            header: Header::new(42, 1000),
+            payload: Payload::Measurement(measurement()),
+        };
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        packet
+            .encode(&mut buf, Protection::Encrypted(key, nonce))
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn nonce() {
+        assert_eq!(Nonce::from_bytes(&NONCE.to_bytes()), NONCE);
+        let mut nonce = Nonce::new(3);
+        assert_eq!(nonce.advance(), Nonce::new(3));
+        assert!(nonce.advance() < nonce);
+        // a new boot outranks any counter of the previous one
+        assert!(Nonce::new(4) > Nonce { epoch: 3, ..nonce });
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytes = encode(&KEY, NONCE);
+        let frame = Frame::parse(&bytes).unwrap();
+        assert!(frame.is_encrypted());
+        assert_eq!(frame.nonce(), Some(NONCE));
+        assert_eq!(frame.packet().unwrap_err(), DecodeError::Encrypted);
+        let Payload::Measurement(mes) = frame.decrypt(&KEY).unwrap().payload else {
+            panic!("not a measurement");
+        };
+        assert_eq!(mes, measurement());
+    }
+
+    #[test]
+    fn hides_payload() {
+        let a = encode(&KEY, NONCE);
+        let b = encode(
+            &KEY,
+            Nonce {
+                counter: 10,
+                ..NONCE
+            },
+        );
+        assert_eq!(a.len(), b.len());
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let bytes = encode(&KEY, NONCE);
+        let frame = Frame::parse(&bytes).unwrap();
+        assert_eq!(
+            frame.decrypt(&[8; auth::KEY_LEN]).unwrap_err(),
+            DecodeError::DecryptionFailed
+        );
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let bytes = encode(&KEY, NONCE);
+        // header (associated data), nonce, ciphertext and tag
+        for i in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 0x01;
+            let decrypted = Frame::parse(&tampered).is_ok_and(|frame| frame.decrypt(&KEY).is_ok());
+            assert!(!decrypted, "byte {i} not covered");
+        }
+    }
+}
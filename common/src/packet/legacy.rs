@@ -0,0 +1,319 @@
+//! Frozen wire layouts of older protocol versions,
+//! decoded and converted into the current types.
+
+use super::{
+    BatchSample, CompactMeasurement, DeviceInfo, Event, Header, Measurement, MeasurementBatch,
+    Payload, MAGIC,
+};
+
+/// pre-versioned header, the magic is a length-prefixed string
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct HeaderV0 {
+    _magic: String,
+    device_id: u32,
+    timestamp: u64,
+    rel_timestamp: i64,
+}
+
+/// versioned header without sequence number, the fields after the version are varints
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct HeaderV1 {
+    _magic: [u8; 4],
+    version: u8,
+    flags: u8,
+    device_id: u32,
+    timestamp: u64,
+    rel_timestamp: i64,
+}
+
+/// postcard encodes the v0 magic as varint length (4) followed by "BRST"
+pub(super) fn is_v0(buf: &[u8]) -> bool {
+    buf.len() > MAGIC.len() && buf[0] == MAGIC.len() as u8 && buf[1..=MAGIC.len()] == MAGIC
+}
+
+impl From<HeaderV0> for Header {
+    fn from(v0: HeaderV0) -> Self {
+        Self {
+            version: 0,
+            rel_timestamp: v0.rel_timestamp,
+            ..Header::new(v0.device_id, v0.timestamp)
+        }
+    }
+}
+
+impl From<HeaderV1> for Header {
+    fn from(v1: HeaderV1) -> Self {
+        Self {
+            version: v1.version,
+            flags: v1.flags,
+            rel_timestamp: v1.rel_timestamp,
+            ..Header::new(v1.device_id, v1.timestamp)
+        }
+    }
+}
+
+/// payload layouts up to protocol version 2, `Measurement` without the BSEC outputs
+#[derive(Debug, serde::Deserialize)]
+pub(super) enum PayloadV2 {
+    Measurement(MeasurementV2),
+    DeviceInfo(DeviceInfoV3),
+    MeasurementBatch(MeasurementBatchV2),
+    CompactMeasurement(CompactMeasurement),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct MeasurementV2 {
+    temperature: Option<f32>,
+    pressure: Option<f32>,
+    humidity: Option<f32>,
+    air_quality: Option<f32>,
+    bat_voltage: Option<f32>,
+    bat_capacity: Option<f32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct MeasurementBatchV2 {
+    samples: Vec<BatchSampleV2>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BatchSampleV2 {
+    offset: u32,
+    measurement: MeasurementV2,
+}
+
+impl From<PayloadV2> for Payload {
+    fn from(v2: PayloadV2) -> Self {
+        match v2 {
+            PayloadV2::Measurement(mes) => Payload::Measurement(mes.into()),
+            PayloadV2::DeviceInfo(info) => Payload::DeviceInfo(info.into()),
+            PayloadV2::MeasurementBatch(batch) => Payload::MeasurementBatch(MeasurementBatch {
+                samples: batch
+                    .samples
+                    .into_iter()
+                    .map(|s| BatchSample {
+                        offset: s.offset,
+                        measurement: s.measurement.into(),
+                    })
+                    .collect(),
+            }),
+            PayloadV2::CompactMeasurement(mes) => Payload::CompactMeasurement(mes),
+        }
+    }
+}
+
+impl From<MeasurementV2> for Measurement {
+    fn from(v2: MeasurementV2) -> Self {
+        Self {
+            temperature: v2.temperature,
+            pressure: v2.pressure,
+            humidity: v2.humidity,
+            air_quality: v2.air_quality,
+            bat_voltage: v2.bat_voltage,
+            bat_capacity: v2.bat_capacity,
+            ..Default::default()
+        }
+    }
+}
+
+/// payload layouts of protocol version 3, `DeviceInfo` without diagnostics
+#[derive(Debug, serde::Deserialize)]
+pub(super) enum PayloadV3 {
+    Measurement(Measurement),
+    DeviceInfo(DeviceInfoV3),
+    MeasurementBatch(MeasurementBatch),
+    CompactMeasurement(CompactMeasurement),
+    Event(Event),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct DeviceInfoV3 {
+    uptime: u64,
+    report_interval: u64,
+    sample_interval: u64,
+    firmware_version: [u8; 4],
+    bsec_version: [u8; 4],
+    model: [u8; 16],
+    wifi_ssid: Option<[u8; 32]>,
+}
+
+impl From<PayloadV3> for Payload {
+    fn from(v3: PayloadV3) -> Self {
+        match v3 {
+            PayloadV3::Measurement(mes) => Payload::Measurement(mes),
+            PayloadV3::DeviceInfo(info) => Payload::DeviceInfo(info.into()),
+            PayloadV3::MeasurementBatch(batch) => Payload::MeasurementBatch(batch),
+            PayloadV3::CompactMeasurement(mes) => Payload::CompactMeasurement(mes),
+            PayloadV3::Event(event) => Payload::Event(event),
+        }
+    }
+}
+
+impl From<DeviceInfoV3> for DeviceInfo {
+    fn from(v3: DeviceInfoV3) -> Self {
+        Self {
+            uptime: v3.uptime,
+            report_interval: v3.report_interval,
+            sample_interval: v3.sample_interval,
+            firmware_version: v3.firmware_version,
+            bsec_version: v3.bsec_version,
+            model: v3.model,
+            wifi_ssid: v3.wifi_ssid,
+            diagnostics: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{DecodeError, Packet, MAX_DATAGRAM_SIZE, PROTOCOL_VERSION};
+    use serde::Serialize;
+
+    // fields of the legacy layouts as tuples, postcard encodes both the same way
+    type MesV2 = [Option<f32>; 6];
+    type InfoV3 = (u64, u64, u64, [u8; 4], [u8; 4], [u8; 16], Option<[u8; 32]>);
+
+    const MES_V2: MesV2 = [
+        Some(21.5),
+        Some(101325.0),
+        Some(45.0),
+        None,
+        Some(4.1),
+        None,
+    ];
+
+    fn encode<H: Serialize, P: Serialize>(header: &H, payload: &P) -> Vec<u8> {
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        let mut bytes = postcard::to_slice(header, &mut buf).unwrap().to_vec();
+        bytes.extend_from_slice(postcard::to_slice(payload, &mut buf).unwrap());
+        bytes
+    }
+
+    fn header(version: u8) -> Header {
+        Header {
+            version,
+            boot_id: 3,
+            sequence: 9,
+            rel_timestamp: -5,
+            ..Header::new(7, 1000)
+        }
+    }
+
+    fn assert_mes_v2(mes: &Measurement) {
+        assert_eq!(
+            *mes,
+            Measurement {
+                temperature: Some(21.5),
+                pressure: Some(101325.0),
+                humidity: Some(45.0),
+                bat_voltage: Some(4.1),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_v0() {
+        let bytes = encode(&("BRST", 7u32, 1000u64, -5i64), &(0u8, MES_V2));
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!(packet.header.version(), 0);
+        assert!(!packet.header.has_sequence());
+        assert_eq!(packet.header.device_id, 7);
+        assert_eq!(packet.header.timestamp, 1000);
+        assert_eq!(packet.header.rel_timestamp, -5);
+        let Payload::Measurement(mes) = packet.payload else {
+            panic!("not a measurement: {:?}", packet.payload);
+        };
+        assert_mes_v2(&mes);
+    }
+
+    #[test]
+    fn decodes_v1() {
+        let header = (MAGIC, 1u8, 0u8, 7u32, 1000u64, -5i64);
+        let bytes = encode(&header, &(2u8, vec![(0u32, MES_V2), (60_000u32, MES_V2)]));
+        let packet = Packet::decode(&bytes).unwrap();
+        assert_eq!(packet.header.version(), 1);
+        assert!(!packet.header.has_sequence());
+        assert_eq!(packet.header.device_id, 7);
+        assert_eq!(packet.header.rel_timestamp, -5);
+        let Payload::MeasurementBatch(batch) = packet.payload else {
+            panic!("not a batch: {:?}", packet.payload);
+        };
+        assert_eq!(batch.samples.len(), 2);
+        assert_eq!(batch.samples[1].offset, 60_000);
+        assert_mes_v2(&batch.samples[1].measurement);
+    }
+
+    #[test]
+    fn decodes_payload_v2() {
+        let mut model = [0; 16];
+        model[..6].copy_from_slice(b"BME680");
+        let info: InfoV3 = (3600, 900, 300, [1, 2, 3, 0], [2, 4, 1, 0], model, None);
+        let bytes = encode(&header(2), &(1u8, info));
+        let packet = Packet::decode(&bytes).unwrap();
+        assert!(packet.header.has_sequence());
+        assert_eq!((packet.header.boot_id, packet.header.sequence), (3, 9));
+        let Payload::DeviceInfo(info) = packet.payload else {
+            panic!("not a device info: {:?}", packet.payload);
+        };
+        assert_eq!(info.uptime, 3600);
+        assert_eq!(info.sample_interval, 300);
+        assert_eq!(info.firmware_version, [1, 2, 3, 0]);
+        assert_eq!(info.model, model);
+        assert!(info.diagnostics.is_none());
+
+        let bytes = encode(&header(2), &(0u8, MES_V2));
+        let Payload::Measurement(mes) = Packet::decode(&bytes).unwrap().payload else {
+            panic!("not a measurement");
+        };
+        assert_mes_v2(&mes);
+    }
+
+    #[test]
+    fn decodes_payload_v3() {
+        let mes = Measurement {
+            temperature: Some(21.5),
+            iaq: Some(42.0),
+            iaq_accuracy: Some(3),
+            co2: Some(600.0),
+            stabilization_status: Some(true),
+            ..Default::default()
+        };
+        let bytes = encode(&header(3), &(0u8, &mes));
+        let Payload::Measurement(decoded) = Packet::decode(&bytes).unwrap().payload else {
+            panic!("not a measurement");
+        };
+        assert_eq!(decoded, mes);
+
+        let info: InfoV3 = (60, 900, 300, [1, 0, 0, 0], [2, 4, 1, 0], [0; 16], None);
+        let bytes = encode(&header(3), &(1u8, info));
+        let Payload::DeviceInfo(info) = Packet::decode(&bytes).unwrap().payload else {
+            panic!("not a device info");
+        };
+        assert_eq!(info.uptime, 60);
+        assert!(info.diagnostics.is_none());
+
+        let event = Event::WifiFailure { attempts: 5 };
+        let bytes = encode(&header(3), &(4u8, &event));
+        let Payload::Event(decoded) = Packet::decode(&bytes).unwrap().payload else {
+            panic!("not an event");
+        };
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let version = PROTOCOL_VERSION + 1;
+        let bytes = encode(&header(version), &(0u8, Measurement::default()));
+        assert_eq!(
+            Packet::decode(&bytes).unwrap_err(),
+            DecodeError::UnsupportedVersion(version)
+        );
+        let bytes = encode(&("BRSX", 7u32, 1000u64, -5i64), &(0u8, MES_V2));
+        assert_eq!(
+            Packet::decode(&bytes).unwrap_err(),
+            DecodeError::InvalidMagic
+        );
+    }
+}
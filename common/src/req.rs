@@ -13,6 +13,7 @@ pub struct DeviceInfo {
     pub report_interval: i32, // s
     pub sample_interval: i32, // s
     pub last_seen: i64,       // s
+    pub source: String,       // udp (incl. HTTP ingestion) or mqtt (third-party sensors)
 }
 
 #[allow(unused)]
@@ -29,6 +30,15 @@ pub enum MeasurementType {
 
     // calculated
     DewPoint = 1 << 6,
+
+    // from sensor data (BSEC)
+    Iaq = 1 << 7,
+    IaqAccuracy = 1 << 8,
+    Co2 = 1 << 9,
+    Voc = 1 << 10,
+    RawGas = 1 << 11,
+    StabilizationStatus = 1 << 12,
+    RunInStatus = 1 << 13,
 }
 
 #[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
@@ -66,10 +76,122 @@ impl Default for MeasurementMask {
     }
 }
 
+/// How `/api/measurements/by_date` reduces the time range to `limit` points
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Downsampling {
+    /// Average, minimum and maximum of equally long time buckets
+    #[default]
+    Bucket,
+    /// Largest triangle three buckets, keeps the samples shaping the first requested series
+    /// of the latest 100000 measurements
+    Lttb,
+    /// Most recent samples
+    Latest,
+}
+
+/// Series keyed by `MeasurementType`, newest first
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 #[allow(unused)]
 pub struct MeasurementRequestResponse {
     pub device_id: i32,
-    pub timestamps: Vec<i64>,
+    pub timestamps: Vec<i64>, // ms since epoch, average of the bucket if downsampled by bucket
     pub data: HashMap<u32, Vec<Option<f32>>>,
+    // bands of the buckets, empty for the other modes
+    #[serde(default)]
+    pub min: HashMap<u32, Vec<Option<f32>>>,
+    #[serde(default)]
+    pub max: HashMap<u32, Vec<Option<f32>>>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct LinkStats {
+    pub device_id: i32,
+    pub received: i64,   // packets
+    pub missing: i64,    // packets, gaps in the sequence
+    pub duplicates: i64, // packets, dropped
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct DeviceDiagnostics {
+    pub device_id: i32,
+    pub timestamp: i64,    // ms since epoch
+    pub rssi: Option<i32>, // dBm
+    pub connect_attempts: i32,
+    pub connect_duration: i32, // ms
+    pub free_heap: i64,        // bytes
+    pub reset_reason: crate::packet::ResetReason,
+}
+
+#[allow(unused)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize, TryFromPrimitive,
+)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum CommandStatus {
+    Pending = 0,
+    Sent = 1,
+    Acknowledged = 2,
+    Rejected = 3,
+    Expired = 4, // not acknowledged after several attempts
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct DeviceCommand {
+    pub id: i32,
+    pub device_id: i32,
+    pub command: crate::packet::command::Command,
+    pub status: CommandStatus,
+    pub attempts: i32,
+    pub created_at: i64, // ms since epoch
+    pub updated_at: i64, // ms since epoch
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct DeviceEvent {
+    pub device_id: i32,
+    pub timestamp: i64, // ms since epoch
+    pub event: crate::packet::Event,
+}
+
+/// Counters of the packet ingestion since the backend started
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct IngestStats {
+    pub received: u64,       // datagrams
+    pub stored: u64,         // packets
+    pub duplicates: u64,     // packets, dropped
+    pub decode_errors: u64,  // datagrams, dropped
+    pub rejected: u64,       // datagrams, failed authentication or replayed
+    pub invalid_fields: u64, // packets, skipped
+    pub db_conflicts: u64,
+    pub db_errors: u64,
+}
+
+/// Outcome of the latest run of the retention job, `GET /api/admin/retention`
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct RetentionReport {
+    pub started_at: i64,  // ms since epoch
+    pub finished_at: i64, // ms since epoch
+    pub raw: u64,         // measurements deleted
+    pub hourly: u64,      // hourly rollups deleted
+    pub daily: u64,       // daily rollups deleted
+    pub journal: u64,     // journal entries deleted
+    pub vacuumed: bool,
+    pub freed_bytes: u64, // by the vacuum
+    pub error: Option<String>,
+}
+
+/// Response of `POST /api/ingest`
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[allow(unused)]
+pub struct IngestResponse {
+    pub duplicate: bool, // already received, not stored again
+    pub ack: Option<crate::packet::Ack>,
 }
@@ -179,6 +179,14 @@ pub fn sensor_control(ts: Duration, sensor: &mut bme680::Device) -> Result<(Outp
         } else {
             let mes = sensor.read_measurements()?;
 
+            let gas_invalid = sensor_settings.run_gas == 1 && mes.gas_res.is_none();
+            let out_of_range = [mes.temperature, mes.pressure, mes.humidity]
+                .iter()
+                .any(|v| v.is_some_and(|v| !v.is_finite()));
+            if gas_invalid || out_of_range {
+                bail!(SensorError::Invalid);
+            }
+
             // feed data to do_steps (i.e., to be processed by bsec)
             let mut bsec_inputs = heapless::Vec::<Input, 4>::new();
             if (sensor_settings.process_data & sys::PROCESS_GAS) > 0 && mes.gas_res.is_some() {
@@ -231,9 +239,27 @@ pub fn sensor_control(ts: Duration, sensor: &mut bme680::Device) -> Result<(Outp
         }
     }
 
-    bail!("sensor busy")
+    bail!(SensorError::Busy)
+}
+
+/// Measurement failures reported to the backend
+#[derive(Debug, Clone, Copy)]
+pub enum SensorError {
+    Busy,
+    Invalid,
 }
 
+impl std::fmt::Display for SensorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Busy => write!(f, "sensor busy"),
+            Self::Invalid => write!(f, "invalid sensor data"),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
 pub fn version() -> Result<[u8; 4]> {
     let mut version = unsafe { std::mem::zeroed::<sys::bsec_version_t>() };
     let ret = unsafe { sys::bsec_get_version(&mut version) };
@@ -342,6 +368,15 @@ impl SampleRate {
     }
 }
 
+impl From<common::packet::command::SampleRate> for SampleRate {
+    fn from(rate: common::packet::command::SampleRate) -> Self {
+        match rate {
+            common::packet::command::SampleRate::Ulp => SampleRate::Ulp,
+            common::packet::command::SampleRate::Lp => SampleRate::Lp,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct VirtualSensorConfiguration {
     pub sample_rate: SampleRate,
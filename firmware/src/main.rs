@@ -17,7 +17,11 @@ use std::time::Duration;
 use crate::rgb_led::Color;
 use crate::utils::LightSleep;
 use crate::wifi::{Credential, WiFi};
-use common::packet::{DeviceInfo, Header, Measurement, Packet, Payload};
+use common::packet::{
+    auth,
+    command::{Command, CommandAck},
+    DeviceInfo, Diagnostics, Event, Header, Measurement, Packet, Payload,
+};
 
 // --------------------------------------------------------------------
 // config
@@ -29,9 +33,15 @@ const MES_REPORT_INTERVAL_DIV: u32 = 3;
 
 const ENABLE_WIFI: bool = true;
 const ENABLE_LIGHT_SLEEP: bool = true;
+const ENABLE_ENCRYPTION: bool = true; // requires DEVICE_KEY
+const ENABLE_ACK: bool = true; // keep packets until acked by the backend
 
 const DEVICE_MODEL: &str = "M1S1";
 
+const LOW_BATTERY_CAPACITY: f32 = 10.0; // percent
+
+const COMMAND_WINDOW: Duration = Duration::from_millis(500); // listen after each report
+
 // --------------------------------------------------------------------
 // pin definitions
 // --------------------------------------------------------------------
@@ -79,52 +89,12 @@ fn main() -> anyhow::Result<()> {
     bsec2::init()?;
     bsec2::set_generic_config_3v3_300s_4d()?;
     let bsec_version = bsec2::version()?;
-    let mes_interval = MES_SAMPLE_RATE.sample_time_interval().unwrap();
+    let mut mes_interval = MES_SAMPLE_RATE.sample_time_interval().unwrap();
     info!(
         "bsec2 v{}.{}.{}.{}",
         bsec_version[0], bsec_version[1], bsec_version[2], bsec_version[3]
     );
-    {
-        use bsec2::*;
-
-        let sample_rate = MES_SAMPLE_RATE;
-
-        let _sensor_inputs = bsec2::update_subscription(&[
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::HeatCompensatedTemperature,
-            },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::HeatCompensatedHumidity,
-            },
-            // VirtualSensorConfiguration {
-            //     sample_rate,
-            //     sensor: VirtualSensor::Voc,
-            // },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::StaticIAQ,
-            },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::RawGas,
-            },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::RawPressure,
-            },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::RawTemperature,
-            },
-            VirtualSensorConfiguration {
-                sample_rate,
-                sensor: VirtualSensor::StabilizationStatus,
-            },
-        ])?;
-        // dbg!(sensor_inputs);
-    }
+    subscribe(MES_SAMPLE_RATE)?;
 
     // peripherals
     let peripherals = Peripherals::take().unwrap();
@@ -191,8 +161,10 @@ fn main() -> anyhow::Result<()> {
         })
         .collect();
 
+    let boot_epoch = utils::next_boot_epoch(nvs.clone())?;
+
     let sys_loop = EspSystemEventLoop::take()?;
-    let mut wifi = WiFi::new(peripherals.modem, sys_loop, credentials, Some(nvs))?;
+    let mut wifi = WiFi::new(peripherals.modem, sys_loop, credentials, Some(nvs.clone()))?;
     if ENABLE_WIFI {
         wifi.start()?;
         wifi.connect()?;
@@ -203,8 +175,26 @@ fn main() -> anyhow::Result<()> {
 
     std::thread::sleep(Duration::from_millis(3000));
 
-    // multicast
-    let mut mc_client = multicast::Client::new()?;
+    // multicast, packets are authenticated or encrypted if a key is provisioned
+    let device_key = env::var("DEVICE_KEY")
+        .ok()
+        .and_then(|key| auth::key_from_hex(&key));
+    if device_key.is_none() {
+        log::warn!("No DEVICE_KEY set, packets are sent unauthenticated");
+    }
+    let mut mc_client = multicast::Client::new(
+        boot_epoch,
+        device_key,
+        ENABLE_ENCRYPTION,
+        device_id,
+        utils::last_command_id(nvs.clone())?,
+        ENABLE_ACK,
+    )?;
+    let reset_reason = utils::reset_reason();
+    mc_client.enqueue_event(
+        Header::new(device_id, utils::system_time().as_millis() as u64),
+        Event::Boot { reset_reason },
+    )?;
 
     // bme680
     bme680.setup(&bme680::Config::default())?;
@@ -213,53 +203,107 @@ fn main() -> anyhow::Result<()> {
     let mut bat = bat::BatMonitor::new(peripherals.adc1, peripherals.pins.gpio1)?;
 
     let mut report_interval = 0;
+    let mut report_div = MES_REPORT_INTERVAL_DIV;
     let mut _report_lock = None;
     let mut next_sample_instant = Duration::ZERO;
+    let mut low_battery_reported = false;
     let startup_time = utils::system_time();
 
     loop {
         // sample?
         if next_sample_instant.saturating_sub(utils::system_time()) == Duration::ZERO {
             log::info!("sample: {:?}", utils::system_time());
-            let (outputs, next_call) = bsec2::sensor_control(utils::system_time(), &mut bme680)?;
+            let (outputs, next_call) =
+                match bsec2::sensor_control(utils::system_time(), &mut bme680) {
+                    Ok(res) => res,
+                    Err(err) => {
+                        // report and skip this sample
+                        let event = match err.downcast_ref::<bsec2::SensorError>() {
+                            Some(bsec2::SensorError::Busy) => Event::SensorBusy,
+                            Some(bsec2::SensorError::Invalid) => Event::SensorInvalid,
+                            None => return Err(err),
+                        };
+                        mc_client.enqueue_event(
+                            Header::new(device_id, utils::system_time().as_millis() as u64),
+                            event,
+                        )?;
+                        next_sample_instant = utils::system_time() + mes_interval;
+                        continue;
+                    }
+                };
             next_sample_instant = next_call;
             let (bat_cap, bat_v) = bat.capacity()?;
 
+            // report once per discharge
+            if bat_cap < LOW_BATTERY_CAPACITY && !low_battery_reported {
+                mc_client.enqueue_event(
+                    Header::new(device_id, utils::system_time().as_millis() as u64),
+                    Event::LowBattery {
+                        voltage: bat_v,
+                        capacity: bat_cap,
+                    },
+                )?;
+            }
+            low_battery_reported = bat_cap < LOW_BATTERY_CAPACITY;
+
             log::info!(
                 "current ts: {:?}, next ts {:?}",
                 utils::system_time(),
                 next_call
             );
 
-            mc_client.enqueue(Packet {
-                header: Header::new(device_id, utils::system_time().as_millis() as u64),
-                payload: Payload::Measurement(Measurement {
+            mc_client.enqueue_measurement(
+                Header::new(device_id, utils::system_time().as_millis() as u64),
+                Measurement {
                     temperature: outputs.heat_compensated_temperature.map(|f| f.signal),
                     pressure: outputs.raw_pressure.map(|f| f.signal),
                     humidity: outputs.heat_compensated_humidity.map(|f| f.signal),
-                    air_quality: outputs.static_iaq.map(|f| f.signal),
+                    air_quality: outputs.static_iaq.as_ref().map(|f| f.signal),
                     bat_voltage: Some(bat_v),
                     bat_capacity: Some(bat_cap),
-                }),
-            })?;
+                    iaq: outputs.iaq.as_ref().map(|f| f.signal),
+                    iaq_accuracy: outputs
+                        .iaq
+                        .as_ref()
+                        .or(outputs.static_iaq.as_ref())
+                        .map(|f| f.accuracy as u8),
+                    co2: outputs.co2.map(|f| f.signal),
+                    voc: outputs.voc.map(|f| f.signal),
+                    raw_gas: outputs.raw_gas.map(|f| f.signal),
+                    stabilization_status: outputs.stabilization_status.map(|f| f.signal > 0.0),
+                    run_in_status: outputs.run_in_status.map(|f| f.signal > 0.0),
+                },
+            )?;
 
             // send?
             report_interval += 1;
-            if report_interval == MES_REPORT_INTERVAL_DIV {
+            if report_interval >= report_div {
                 report_interval = 0;
 
-                mc_client.enqueue(Packet {
-                    header: Header::new(device_id, utils::system_time().as_millis() as u64),
-                    payload: Payload::DeviceInfo(DeviceInfo {
-                        uptime: (utils::system_time() - startup_time).as_secs(),
-                        firmware_version: fw_version,
-                        bsec_version,
-                        model,
-                        wifi_ssid: wifi.ssid().as_ref().map(|ssid| to_array(ssid.as_bytes())),
-                        report_interval: mes_interval.as_secs() * MES_REPORT_INTERVAL_DIV as u64,
-                        sample_interval: mes_interval.as_secs(),
-                    }),
-                })?;
+                // diagnostics of the previous connection, this report is sent afterwards
+                let device_info = |wifi: &WiFi, mes_interval: Duration, report_div: u32| {
+                    let (rssi, connect_attempts, connect_duration) = wifi.diagnostics();
+                    Packet {
+                        header: Header::new(device_id, utils::system_time().as_millis() as u64),
+                        payload: Payload::DeviceInfo(DeviceInfo {
+                            uptime: (utils::system_time() - startup_time).as_secs(),
+                            firmware_version: fw_version,
+                            bsec_version,
+                            model,
+                            wifi_ssid: wifi.ssid().as_ref().map(|ssid| to_array(ssid.as_bytes())),
+                            report_interval: mes_interval.as_secs() * report_div as u64,
+                            sample_interval: mes_interval.as_secs(),
+                            diagnostics: Some(Diagnostics {
+                                rssi,
+                                connect_attempts,
+                                connect_duration: connect_duration.as_millis() as u32,
+                                free_heap: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+                                reset_reason,
+                            }),
+                        }),
+                    }
+                };
+                mc_client.enqueue(device_info(&wifi, mes_interval, report_div))?;
 
                 // prevent sleep until data has been transmitted
                 if ENABLE_LIGHT_SLEEP {
@@ -272,24 +316,113 @@ fn main() -> anyhow::Result<()> {
                     _report_lock = Some(lightsleep.lock());
                 }
 
+                let mut identify = Duration::ZERO;
+                let mut reboot = false;
                 if ENABLE_WIFI {
                     wifi.start()?;
                     wifi.connect()?;
 
-                    log::info!("Broadcast pkgs");
-                    mc_client
-                        .broadcast_queue()
-                        .expect("Cannot transfer packets");
+                    if wifi.is_connected() {
+                        log::info!("Broadcast pkgs");
+                        // the backend answers every report with an ack and pending commands,
+                        // which are acked with another report
+                        loop {
+                            mc_client
+                                .broadcast_queue()
+                                .expect("Cannot transfer packets");
+
+                            let commands = mc_client.receive(COMMAND_WINDOW)?;
+                            let Some(last) = commands.last() else {
+                                break;
+                            };
+                            utils::set_last_command_id(nvs.clone(), last.id)?;
+
+                            for cmd in &commands {
+                                log::info!("Command: {:?}", cmd);
+                                let accepted = match cmd.command {
+                                    Command::SetSampleRate(rate) => {
+                                        let rate = bsec2::SampleRate::from(rate);
+                                        match subscribe(rate) {
+                                            Ok(()) => {
+                                                mes_interval = rate.sample_time_interval().unwrap();
+                                                true
+                                            }
+                                            Err(err) => {
+                                                log::warn!("Cannot change sample rate: {}", err);
+                                                false
+                                            }
+                                        }
+                                    }
+                                    Command::SetReportDivider(div) => {
+                                        if div > 0 {
+                                            report_div = div;
+                                        }
+                                        div > 0
+                                    }
+                                    Command::Identify { duration } => {
+                                        identify = Duration::from_secs(duration as u64);
+                                        true
+                                    }
+                                    Command::Reboot => {
+                                        reboot = true;
+                                        true
+                                    }
+                                    Command::RequestDeviceInfo => {
+                                        mc_client.enqueue(device_info(
+                                            &wifi,
+                                            mes_interval,
+                                            report_div,
+                                        ))?;
+                                        true
+                                    }
+                                };
+                                mc_client.enqueue_command_ack(
+                                    Header::new(device_id, utils::system_time().as_millis() as u64),
+                                    CommandAck {
+                                        id: cmd.id,
+                                        accepted,
+                                    },
+                                )?;
+                            }
+                        }
+                    } else {
+                        // keep the queue for the next report
+                        mc_client.enqueue_event(
+                            Header::new(device_id, utils::system_time().as_millis() as u64),
+                            Event::WifiFailure {
+                                attempts: wifi::CONNECT_ATTEMPTS,
+                            },
+                        )?;
+                    }
 
                     wifi.stop().expect("Cannot stop wifi");
 
+                    if reboot {
+                        log::info!("Reboot requested");
+                        esp_idf_hal::reset::restart();
+                    }
+
+                    // blink until the duration has passed
+                    let blink_until = utils::system_time() + identify;
+                    while utils::system_time() < blink_until {
+                        led.set_color(&Color::White);
+                        std::thread::sleep(Duration::from_millis(500));
+                        led.set_color(&Color::Black);
+                        std::thread::sleep(Duration::from_millis(500));
+                    }
+
                     // drop the sleep lock, triggers sleep
                     _report_lock = None;
                 }
             }
 
             if ENABLE_LIGHT_SLEEP {
-                lightsleep.sleep_until(next_call);
+                while lightsleep.sleep_until(next_call) {
+                    mc_client.enqueue_event(
+                        Header::new(device_id, utils::system_time().as_millis() as u64),
+                        Event::ButtonPressed,
+                    )?;
+                }
             }
         }
 
@@ -304,3 +437,56 @@ fn to_array<const N: usize>(s: &[u8]) -> [u8; N] {
     a[0..s.len()].copy_from_slice(s);
     a
 }
+
+/// Subscribes to all used BSEC outputs at the given rate
+fn subscribe(sample_rate: bsec2::SampleRate) -> anyhow::Result<()> {
+    use bsec2::*;
+
+    let _sensor_inputs = bsec2::update_subscription(&[
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::HeatCompensatedTemperature,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::HeatCompensatedHumidity,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::Voc,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::Co2,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::IAQ,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::StaticIAQ,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::RawGas,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::RawPressure,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::RawTemperature,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::StabilizationStatus,
+        },
+        VirtualSensorConfiguration {
+            sample_rate,
+            sensor: VirtualSensor::RunInStatus,
+        },
+    ])?;
+    Ok(())
+}
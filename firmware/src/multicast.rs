@@ -1,34 +1,77 @@
 use anyhow::Result;
 use std::net::UdpSocket;
+use std::time::{Duration, Instant};
 
-use common::packet::Packet;
+use common::packet::{
+    auth,
+    command::{CommandAck, CommandRequest, COMMAND_PORT},
+    crypto, Ack, BatchSample, Event, Frame, Header, Measurement, MeasurementBatch, Packet, Payload,
+    Protection, MAX_DATAGRAM_SIZE,
+};
 
 pub struct Client {
-    socket: UdpSocket, // 508 bytes (single fragment)
+    socket: UdpSocket,
     command_socket: UdpSocket,
     queue: heapless::Vec<Packet, 40>,
+    key: Option<auth::Key>,
+    nonce: Option<crypto::Nonce>, // encrypt if set
+    boot_id: u32,
+    sequence: u32,
+    device_id: u32,
+    last_command_id: u32,
+    acknowledged: bool, // keep packets until acked by the backend
+    sent: usize,        // queued packets already broadcast, awaiting an ack
 }
 
 impl Client {
-    pub fn new() -> Result<Self> {
+    pub fn new(
+        boot_id: u32,
+        key: Option<auth::Key>,
+        encrypt: bool,
+        device_id: u32,
+        last_command_id: u32,
+        acknowledged: bool,
+    ) -> Result<Self> {
         // x.x.x.255?
 
         let socket = UdpSocket::bind("0.0.0.0:8989")?;
         socket.connect("192.168.178.255:8989")?;
         socket.set_broadcast(true)?;
 
-        let command_socket = UdpSocket::bind("192.168.178.255:6464")?;
+        let command_socket = UdpSocket::bind(("0.0.0.0", COMMAND_PORT))?;
 
         Ok(Self {
             socket,
             command_socket,
             queue: heapless::Vec::new(),
+            key,
+            nonce: encrypt.then(|| crypto::Nonce::new(boot_id)),
+            boot_id,
+            sequence: 0,
+            device_id,
+            last_command_id,
+            acknowledged,
+            sent: 0,
         })
     }
 
-    pub fn broadcast_pkt(&self, payload: &Packet) -> Result<()> {
-        let buffer: heapless::Vec<u8, 256> = postcard::to_vec(payload)?;
-        self.socket.send(&buffer)?;
+    fn protection(&self) -> Protection<'_> {
+        match (&self.key, self.nonce) {
+            (Some(key), Some(nonce)) => Protection::Encrypted(key, nonce),
+            (Some(key), None) => Protection::Authenticated(key),
+            _ => Protection::Plain,
+        }
+    }
+
+    pub fn broadcast_pkt(&mut self, payload: &Packet) -> Result<()> {
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        let buffer = payload.encode(&mut buffer, self.protection())?;
+
+        // never reuse a nonce
+        if let Some(nonce) = &mut self.nonce {
+            nonce.advance();
+        }
+        self.socket.send(buffer)?;
 
         Ok(())
     }
@@ -36,14 +79,36 @@ impl Client {
     pub fn broadcast_queue(&mut self) -> Result<()> {
         self.timestamp_to_rel();
 
-        for pkt in &self.queue {
+        let queue = std::mem::take(&mut self.queue);
+        for pkt in &queue {
             self.broadcast_pkt(pkt)?;
         }
-        self.queue.clear();
+
+        // retransmitted with the next report unless acked in the meantime
+        if self.acknowledged {
+            self.queue = queue;
+            self.sent = self.queue.len();
+        }
 
         Ok(())
     }
 
+    /// Drops the queued packets up to the ack, the backend received all of them
+    fn acknowledge(&mut self, ack: &Ack) {
+        if ack.boot_id != self.boot_id {
+            return;
+        }
+
+        let len = self.queue.len();
+        self.queue.retain(|pkt| pkt.header.sequence > ack.sequence);
+        self.sent = self.sent.saturating_sub(len - self.queue.len());
+        log::info!(
+            "Ack up to {}, {} packets pending",
+            ack.sequence,
+            self.queue.len()
+        );
+    }
+
     fn timestamp_to_rel(&mut self) {
         let last_timestamp = self.queue.last().map_or(0, |pkg| pkg.header.timestamp);
         for pkt in &mut self.queue {
@@ -51,12 +116,116 @@ impl Client {
         }
     }
 
-    pub fn enqueue(&mut self, pkt: Packet) -> Result<()> {
-        if let Err(err) = self.queue.push(pkt) {
-            self.queue.clear();
-            log::warn!("Broadcast queue cleared. Reason: {:?}", err);
-            // TODO: error
+    pub fn enqueue(&mut self, mut pkt: Packet) -> Result<()> {
+        pkt.header.boot_id = self.boot_id;
+        pkt.header.sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        // evict the oldest packet to make room
+        if let Err(pkt) = self.queue.push(pkt) {
+            let evicted = self.queue.remove(0);
+            self.sent = self.sent.saturating_sub(1);
+            log::warn!(
+                "Broadcast queue full, dropped packet {}",
+                evicted.header.sequence
+            );
+            let _ = self.queue.push(pkt);
         };
         Ok(())
     }
+
+    pub fn enqueue_event(&mut self, header: Header, event: Event) -> Result<()> {
+        log::info!("Event: {:?}", event);
+        self.enqueue(Packet {
+            header,
+            payload: Payload::Event(event),
+        })
+    }
+
+    pub fn enqueue_command_ack(&mut self, header: Header, ack: CommandAck) -> Result<()> {
+        log::info!("Command ack: {:?}", ack);
+        self.enqueue(Packet {
+            header,
+            payload: Payload::CommandAck(ack),
+        })
+    }
+
+    /// Collects the commands addressed to this device until the window closes and
+    /// applies acks, unauthenticated packets and replayed commands are dropped
+    pub fn receive(&mut self, window: Duration) -> Result<Vec<CommandRequest>> {
+        let deadline = Instant::now() + window;
+        let mut commands = Vec::new();
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+
+        while let Some(timeout) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|t| !t.is_zero())
+        {
+            self.command_socket.set_read_timeout(Some(timeout))?;
+            let len = match self.command_socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            match self.parse(&buffer[..len]) {
+                Ok(Some(Payload::Command(cmd))) if cmd.id > self.last_command_id => {
+                    self.last_command_id = cmd.id;
+                    commands.push(cmd);
+                }
+                Ok(Some(Payload::Command(cmd))) => {
+                    log::info!("Ignored replayed command {}", cmd.id);
+                }
+                Ok(Some(Payload::Ack(ack))) => self.acknowledge(&ack),
+                Ok(_) => (),
+                Err(err) => log::warn!("Dropped packet: {}", err),
+            }
+        }
+
+        Ok(commands)
+    }
+
+    fn parse(&self, buf: &[u8]) -> Result<Option<Payload>> {
+        let frame = Frame::parse(buf)?;
+        if frame.header.device_id != self.device_id {
+            return Ok(None);
+        }
+        if let Some(key) = &self.key {
+            if !frame.verify(key) {
+                anyhow::bail!("authentication failed");
+            }
+        }
+
+        Ok(Some(frame.packet()?.payload))
+    }
+
+    /// Adds the sample to the last queued batch, starts a new one if it is full
+    pub fn enqueue_measurement(&mut self, header: Header, measurement: Measurement) -> Result<()> {
+        // broadcast packets are immutable, their sequence may have been received
+        let overhead = self.protection().overhead();
+        if self.queue.len() > self.sent {
+            if let Some(pkt) = self.queue.last_mut() {
+                if pkt.try_push_sample(header.timestamp, &measurement, overhead) {
+                    return Ok(());
+                }
+            }
+        }
+
+        self.enqueue(Packet {
+            header,
+            payload: Payload::MeasurementBatch(MeasurementBatch {
+                samples: vec![BatchSample {
+                    offset: 0,
+                    measurement,
+                }],
+            }),
+        })
+    }
 }
@@ -1,7 +1,9 @@
 use std::time::Duration;
 
 use anyhow::{bail, Result};
+use common::packet::ResetReason;
 use esp_idf_hal::gpio::{AnyIOPin, Input, PinDriver};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
 use esp_idf_sys::esp_efuse_mac_get_default;
 
 pub fn mac_addr() -> Result<[u8; 6]> {
@@ -21,6 +23,32 @@ pub fn device_id() -> Result<u32> {
     Ok(v1 ^ v2)
 }
 
+/// Increments the boot counter persisted in nvs,
+/// identifies the boot in packet headers and keeps nonces unique across reboots
+pub fn next_boot_epoch(nvs: EspDefaultNvsPartition) -> Result<u32> {
+    let storage = EspNvs::new(nvs, "smart_meter", true)?;
+    let epoch = storage.get_u32("boot_epoch")?.unwrap_or(0).wrapping_add(1);
+    storage.set_u32("boot_epoch", epoch)?;
+
+    Ok(epoch)
+}
+
+/// Id of the last executed command, commands with a lower or equal id are replays
+pub fn last_command_id(nvs: EspDefaultNvsPartition) -> Result<u32> {
+    let storage = EspNvs::new(nvs, "smart_meter", true)?;
+    Ok(storage.get_u32("last_command")?.unwrap_or(0))
+}
+
+pub fn set_last_command_id(nvs: EspDefaultNvsPartition, id: u32) -> Result<()> {
+    let storage = EspNvs::new(nvs, "smart_meter", true)?;
+    storage.set_u32("last_command", id)?;
+    Ok(())
+}
+
+pub fn reset_reason() -> ResetReason {
+    ResetReason::from(unsafe { esp_idf_sys::esp_reset_reason() } as u8)
+}
+
 pub fn system_time() -> Duration {
     esp_idf_svc::systime::EspSystemTime {}.now()
 }
@@ -31,7 +59,8 @@ struct SleepShared {
 }
 
 impl SleepShared {
-    fn do_sleep(&self) {
+    /// Returns true if the device went to sleep
+    fn do_sleep(&self) -> bool {
         if let Some(next_wakeup) = self.next_wakeup {
             if self.counter == 0 {
                 if let Some(dur) = next_wakeup.checked_sub(system_time()) {
@@ -40,12 +69,13 @@ impl SleepShared {
                         if esp_idf_sys::esp_sleep_enable_timer_wakeup(dur.as_micros() as u64)
                             == esp_idf_sys::ESP_OK
                         {
-                            esp_idf_sys::esp_light_sleep_start();
+                            return esp_idf_sys::esp_light_sleep_start() == esp_idf_sys::ESP_OK;
                         }
                     };
                 }
             }
         }
+        false
     }
 }
 
@@ -58,7 +88,7 @@ pub fn thread_sleep_until(instant: Duration) {
 
 pub struct LightSleep {
     shared: std::sync::Arc<std::sync::Mutex<SleepShared>>,
-    _pin_driver: PinDriver<'static, AnyIOPin, Input>,
+    pin_driver: PinDriver<'static, AnyIOPin, Input>,
 }
 
 impl LightSleep {
@@ -70,6 +100,7 @@ impl LightSleep {
                 pin_driver.pin(),
                 esp_idf_sys::gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
             ))?;
+            esp_idf_sys::esp!(esp_idf_sys::esp_sleep_enable_gpio_wakeup())?;
         }
 
         let shared = std::sync::Arc::new(std::sync::Mutex::new(SleepShared {
@@ -77,17 +108,29 @@ impl LightSleep {
             next_wakeup: None,
         }));
 
-        Ok(Self {
-            shared,
-            _pin_driver: pin_driver,
-        })
+        Ok(Self { shared, pin_driver })
     }
 
-    pub fn sleep_until(&mut self, instant: Duration) {
-        if let Ok(mut shared) = self.shared.lock() {
-            shared.next_wakeup = Some(instant);
-            shared.do_sleep();
+    /// Returns true if woken up early by the wakeup pin (e.g. a button press)
+    pub fn sleep_until(&mut self, instant: Duration) -> bool {
+        let slept = match self.shared.lock() {
+            Ok(mut shared) => {
+                shared.next_wakeup = Some(instant);
+                shared.do_sleep()
+            }
+            Err(_) => false,
+        };
+
+        let woken_by_pin = slept
+            && unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() }
+                == esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO;
+
+        // the wakeup is level triggered, wait for the release
+        while woken_by_pin && self.pin_driver.is_high() {
+            std::thread::sleep(Duration::from_millis(10));
         }
+
+        woken_by_pin
     }
 
     pub fn lock(&self) -> SleepInhibitor {
@@ -6,6 +6,10 @@ use esp_idf_hal::peripheral;
 use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::BlockingWifi, wifi::EspWifi};
 use heapless::String;
 
+use crate::utils;
+
+pub const CONNECT_ATTEMPTS: u8 = 3;
+
 #[derive(Debug)]
 pub struct Credential {
     pub ssid: String<32>,
@@ -21,6 +25,11 @@ pub struct WiFi {
     cached_channel: Option<u8>,
     cached_bssid: Option<[u8; 6]>,
     cached_strongest_signal: Option<usize>,
+
+    // diagnostics of the last connection
+    rssi: Option<i8>,
+    connect_attempts: u8,
+    connect_duration: Duration,
 }
 
 impl WiFi {
@@ -40,6 +49,9 @@ impl WiFi {
             cached_bssid: None,
             cached_channel: None,
             cached_strongest_signal: None,
+            rssi: None,
+            connect_attempts: 0,
+            connect_duration: Duration::ZERO,
         })
     }
 
@@ -103,19 +115,27 @@ impl WiFi {
             return Ok(());
         }
 
-        for _ in 0..3 {
+        let start = utils::system_time();
+        self.connect_attempts = 0;
+        self.rssi = None;
+
+        for _ in 0..CONNECT_ATTEMPTS {
             log::info!("Connecting...");
+            self.connect_attempts += 1;
 
             if self.wifi.connect().is_err() {
                 log::warn!("Wifi connection failed... try again");
             } else {
                 self.wifi.wait_netif_up()?;
+                self.rssi = Self::ap_rssi();
                 break;
             }
 
             std::thread::sleep(Duration::from_secs(5));
         }
 
+        self.connect_duration = utils::system_time() - start;
+
         // cache for faster reconnect
         self.cached_bssid = self
             .wifi
@@ -151,4 +171,15 @@ impl WiFi {
     pub fn ssid(&self) -> &Option<String<32>> {
         &self.ssid
     }
+
+    /// Signal strength, attempts and duration of the last connection
+    pub fn diagnostics(&self) -> (Option<i8>, u8, Duration) {
+        (self.rssi, self.connect_attempts, self.connect_duration)
+    }
+
+    fn ap_rssi() -> Option<i8> {
+        let mut info = unsafe { std::mem::zeroed::<esp_idf_sys::wifi_ap_record_t>() };
+        let ret = unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) };
+        (ret == esp_idf_sys::ESP_OK).then_some(info.rssi)
+    }
 }
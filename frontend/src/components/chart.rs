@@ -55,6 +55,13 @@ impl Component for Model {
                 MeasurementType::BatVoltage => vec![Overlay::Battery],
                 MeasurementType::AirQuality => vec![Overlay::Iaq],
                 MeasurementType::DewPoint => vec![],
+                MeasurementType::Iaq => vec![Overlay::Iaq],
+                MeasurementType::IaqAccuracy => vec![],
+                MeasurementType::Co2 => vec![Overlay::Stats],
+                MeasurementType::Voc => vec![Overlay::Stats],
+                MeasurementType::RawGas => vec![Overlay::Stats],
+                MeasurementType::StabilizationStatus => vec![],
+                MeasurementType::RunInStatus => vec![],
             }
         };
 
@@ -64,6 +71,11 @@ impl Component for Model {
             MeasurementType::Humidity,
             MeasurementType::Pressure,
             MeasurementType::AirQuality,
+            MeasurementType::Iaq,
+            MeasurementType::IaqAccuracy,
+            MeasurementType::Co2,
+            MeasurementType::Voc,
+            MeasurementType::RawGas,
             MeasurementType::BatVoltage,
         ];
 
@@ -167,6 +179,7 @@ impl Model {
                     Some(to_ts),
                     req::MeasurementMask::ALL,
                     10000,
+                    req::Downsampling::Bucket,
                 )
                 .await
                 .unwrap();
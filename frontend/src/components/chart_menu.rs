@@ -70,6 +70,11 @@ impl Component for Model {
             (" Humidity", MeasurementType::Humidity),
             (" Pressure", MeasurementType::Pressure),
             (" Air Quality", MeasurementType::AirQuality),
+            (" IAQ", MeasurementType::Iaq),
+            (" IAQ Accuracy", MeasurementType::IaqAccuracy),
+            (" CO2 Equivalent", MeasurementType::Co2),
+            (" Breath VOC Equivalent", MeasurementType::Voc),
+            (" Gas Resistance", MeasurementType::RawGas),
             (" Battery Voltage", MeasurementType::BatVoltage),
         ];
 
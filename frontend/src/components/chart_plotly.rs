@@ -2,7 +2,7 @@ use chrono::{DateTime, Local, Utc};
 use common::req::MeasurementType;
 use plotly::{
     color::NamedColor,
-    common::DashType::LongDash,
+    common::{DashType::LongDash, Fill, HoverInfo, Line},
     layout::{Annotation, Axis, Legend, Margin, Shape, ShapeLine},
     Configuration, Layout, Plot, Scatter,
 };
@@ -40,6 +40,7 @@ pub fn chart_plotly(props: &Props) -> Html {
     let series = props.dataset.get(&props.kind).unwrap();
     let p = yew_hooks::use_async::<_, _, ()>({
         let mut plot = Plot::new();
+        add_band(&mut plot, &series.band);
         let trace = Scatter::new(
             series
                 .data
@@ -123,6 +124,29 @@ pub fn chart_plotly(props: &Props) -> Html {
     }
 }
 
+/// Shaded envelope between the min and max of the aggregated points
+fn add_band(plot: &mut Plot, band: &[(i64, f32, f32)]) {
+    if band.is_empty() {
+        return;
+    }
+    let x: Vec<_> = band
+        .iter()
+        .map(|(t, _, _)| DateTime::<Local>::from(utils::utc_from_millis(*t)))
+        .collect();
+    let max = Scatter::new(x.clone(), band.iter().map(|(_, _, max)| *max).collect())
+        .line(Line::new().width(0.0))
+        .hover_info(HoverInfo::Skip)
+        .show_legend(false);
+    let min = Scatter::new(x, band.iter().map(|(_, min, _)| *min).collect())
+        .line(Line::new().width(0.0))
+        .fill(Fill::ToNextY)
+        .fill_color("rgba(31, 119, 180, 0.2)")
+        .hover_info(HoverInfo::Skip)
+        .show_legend(false);
+    plot.add_trace(max);
+    plot.add_trace(min);
+}
+
 fn add_overlay_stats(layout: &mut Layout, props: &Props) {
     let series = props.dataset.get(&props.kind).unwrap();
 
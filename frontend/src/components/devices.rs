@@ -8,7 +8,7 @@ use crate::{
     request,
     utils,
 };
-use common::req::{MeasurementType, MeasurementMask};
+use common::req::{Downsampling, MeasurementType, MeasurementMask};
 
 const NOT_AVAILABLE: &str = "N/A";
 
@@ -50,6 +50,7 @@ pub fn device_list() -> yew::Html {
                         None,
                         MeasurementMask::ALL,
                         1,
+                        Downsampling::Latest,
                     )
                     .await.unwrap();
                     measurements.insert(id, resp);
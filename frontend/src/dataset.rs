@@ -4,6 +4,8 @@ use std::collections::HashMap;
 #[derive(Debug, Clone, PartialEq)]
 pub struct Series {
     pub data: Vec<(i64, f32)>,
+    /// min and max of the aggregated points, empty if not downsampled by buckets
+    pub band: Vec<(i64, f32, f32)>,
     pub kind: MeasurementType,
     pub unit: String,
     pub name: String,
@@ -21,6 +23,7 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
             unit: "°C".to_owned(),
             kind: MeasurementType::Temperature,
             data: vec![],
+            band: vec![],
             scale: 1.0,
         },
     );
@@ -31,6 +34,7 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
             unit: "%".to_owned(),
             kind: MeasurementType::Humidity,
             data: vec![],
+            band: vec![],
             scale: 1.0,
         },
     );
@@ -41,6 +45,7 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
             unit: "hPa".to_owned(),
             kind: MeasurementType::Pressure,
             data: vec![],
+            band: vec![],
             scale: 1e-2,
         },
     );
@@ -51,6 +56,7 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
             unit: "IAQ".to_owned(),
             kind: MeasurementType::AirQuality,
             data: vec![],
+            band: vec![],
             scale: 1.0,
         },
     );
@@ -61,9 +67,65 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
             unit: "V".to_owned(),
             kind: MeasurementType::BatVoltage,
             data: vec![],
+            band: vec![],
             scale: 1.0,
         },
     );
+    dataset.insert(
+        MeasurementType::Iaq,
+        Series {
+            name: "IAQ".to_owned(),
+            unit: "IAQ".to_owned(),
+            kind: MeasurementType::Iaq,
+            data: vec![],
+            band: vec![],
+            scale: 1.0,
+        },
+    );
+    dataset.insert(
+        MeasurementType::IaqAccuracy,
+        Series {
+            name: "IAQ Accuracy".to_owned(),
+            unit: "0..3".to_owned(),
+            kind: MeasurementType::IaqAccuracy,
+            data: vec![],
+            band: vec![],
+            scale: 1.0,
+        },
+    );
+    dataset.insert(
+        MeasurementType::Co2,
+        Series {
+            name: "CO2 Equivalent".to_owned(),
+            unit: "ppm".to_owned(),
+            kind: MeasurementType::Co2,
+            data: vec![],
+            band: vec![],
+            scale: 1.0,
+        },
+    );
+    dataset.insert(
+        MeasurementType::Voc,
+        Series {
+            name: "Breath VOC Equivalent".to_owned(),
+            unit: "ppm".to_owned(),
+            kind: MeasurementType::Voc,
+            data: vec![],
+            band: vec![],
+            scale: 1.0,
+        },
+    );
+    dataset.insert(
+        MeasurementType::RawGas,
+        Series {
+            name: "Gas Resistance".to_owned(),
+            unit: "kΩ".to_owned(),
+            kind: MeasurementType::RawGas,
+            data: vec![],
+            band: vec![],
+            scale: 1e-3,
+        },
+    );
 
     for (k, v) in &resp.data {
         if let Ok(meas_type) = MeasurementType::try_from(*k) {
@@ -76,6 +138,20 @@ pub fn dataset_from_request(resp: &MeasurementRequestResponse) -> Dataset {
                     .zip(v)
                     .map(|(a, b)| (*a, b.map_or(std::f32::NAN, |v| v * scale)))
                     .collect::<Vec<_>>();
+                if let (Some(min), Some(max)) = (resp.min.get(k), resp.max.get(k)) {
+                    dataset.get_mut(&meas_type).unwrap().band = resp
+                        .timestamps
+                        .iter()
+                        .zip(min.iter().zip(max))
+                        .map(|(t, (min, max))| {
+                            (
+                                *t,
+                                min.map_or(f32::NAN, |v| v * scale),
+                                max.map_or(f32::NAN, |v| v * scale),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                }
             }
         }
     }
@@ -152,6 +228,7 @@ pub fn dew_point(rh: &[(i64, f32)], temp: &[(i64, f32)]) -> Series {
                 (*t, (b * alpha) / (a - alpha))
             })
             .collect(),
+        band: vec![],
         kind: MeasurementType::DewPoint,
         unit: "°C".to_string(),
         name: "Dew Point".to_string(),
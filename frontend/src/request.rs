@@ -24,6 +24,7 @@ pub async fn measurements(
     ts_to: Option<DateTime<Utc>>,
     measurement_mask: MeasurementMask,
     limit: i64,
+    downsampling: Downsampling,
 ) -> Result<MeasurementRequestResponse> {
     let client = reqwest::Client::new();
 
@@ -42,6 +43,7 @@ pub async fn measurements(
     Ok(client
         .get(api_url("api/measurements/by_date"))
         .query(&query)
+        .query(&[("downsampling", downsampling)])
         .header(ACCEPT, "application/json")
         .send()
         .await?
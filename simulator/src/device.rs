@@ -0,0 +1,332 @@
+use std::f64::consts::PI;
+
+use anyhow::{anyhow, Result};
+use common::packet::{
+    auth, crypto, DeviceInfo, Diagnostics, Event, Header, Measurement, Packet, Payload, Protection,
+    ResetReason, MAX_DATAGRAM_SIZE,
+};
+use rand::Rng;
+
+use crate::scenario::{Curve, Group};
+
+const DAY: f64 = 86400.0; // s
+const STABILIZATION_TIME: f64 = 300.0; // s, BSEC gas sensor
+const RUN_IN_TIME: f64 = 1800.0; // s, BSEC gas sensor
+
+/// Emulates the firmware of a single sensor on the simulated clock (seconds since start)
+pub struct VirtualDevice {
+    id: u32,
+    config: Group,
+    key: Option<auth::Key>,
+    epoch: f64,      // unix time of the simulation start
+    time_scale: f64, // simulated seconds per second
+    boot_id: u32,
+    boot_time: f64,
+    sequence: u32,
+    nonce: Option<crypto::Nonce>, // encrypt if set
+    reset_reason: ResetReason,
+    queue: Vec<Packet>,
+    samples: u32,
+    next_sample: f64,
+    last_sample: f64,
+    capacity: f64, // battery, percent
+    pub stats: Stats,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub reboots: u64,
+}
+
+impl VirtualDevice {
+    pub fn new(
+        id: u32,
+        config: Group,
+        epoch: f64,
+        time_scale: f64,
+        rng: &mut impl Rng,
+    ) -> Result<Self> {
+        let key = match &config.key {
+            Some(key) => {
+                Some(auth::key_from_hex(key).ok_or_else(|| anyhow!("invalid key of device {id}"))?)
+            }
+            None => None,
+        };
+
+        // increases across runs, the backend rejects nonces that do not
+        let boot_id = epoch as u32;
+        let next_sample = rng.gen_range(0.0..config.sample_interval);
+
+        Ok(Self {
+            id,
+            key,
+            epoch,
+            time_scale,
+            boot_id,
+            boot_time: 0.0,
+            sequence: 0,
+            nonce: config.encrypt.then(|| crypto::Nonce::new(boot_id)),
+            reset_reason: ResetReason::PowerOn,
+            queue: vec![],
+            samples: 0,
+            next_sample,
+            last_sample: 0.0,
+            capacity: config.battery.capacity,
+            config,
+            stats: Stats::default(),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn next_sample(&self) -> f64 {
+        self.next_sample
+    }
+
+    /// Takes a sample at `now` and returns the datagrams that made it onto the network
+    pub fn step(&mut self, now: f64, rng: &mut impl Rng) -> Result<Vec<Vec<u8>>> {
+        if self.samples == 0 && self.queue.is_empty() {
+            self.enqueue(
+                now,
+                Payload::Event(Event::Boot {
+                    reset_reason: self.reset_reason,
+                }),
+            );
+        }
+
+        self.capacity -= self.config.battery.drain * (now - self.last_sample) / DAY;
+        self.last_sample = now;
+        if self.capacity <= 0.0 {
+            // batteries replaced
+            self.capacity = 100.0;
+            self.reboot(now, ResetReason::Brownout);
+            return Ok(vec![]);
+        }
+
+        let measurement = self.measure(now, rng);
+        self.enqueue(now, Payload::Measurement(measurement));
+        self.samples += 1;
+
+        let jitter = rng.gen_range(-1.0..=1.0) * self.config.jitter;
+        self.next_sample = now + (self.config.sample_interval + jitter).max(1.0);
+
+        if !self.samples.is_multiple_of(self.config.report_divider) {
+            return Ok(vec![]);
+        }
+
+        let info = self.device_info(now, rng);
+        self.enqueue(now, Payload::DeviceInfo(info));
+        let datagrams = self.report(now, rng)?;
+
+        if rng.gen_bool(self.config.reboot_rate.clamp(0.0, 1.0)) {
+            self.reboot(now, ResetReason::Panic);
+        }
+
+        Ok(datagrams)
+    }
+
+    fn uptime(&self, now: f64) -> f64 {
+        now - self.boot_time
+    }
+
+    fn measure(&self, now: f64, rng: &mut impl Rng) -> Measurement {
+        let hour = (self.epoch + now).rem_euclid(DAY) / 3600.0;
+        let uptime = self.uptime(now);
+
+        let temperature = sample(&self.config.temperature, hour, rng);
+        let humidity = sample(&self.config.humidity, hour, rng).clamp(0.0, 100.0);
+        let pressure = sample(&self.config.pressure, hour, rng);
+        let iaq = sample(&self.config.iaq, hour, rng).clamp(0.0, 500.0);
+        let iaq_accuracy = match uptime {
+            t if t < STABILIZATION_TIME => 0,
+            t if t < RUN_IN_TIME => 1,
+            t if t < 2.0 * RUN_IN_TIME => 2,
+            _ => 3,
+        };
+
+        Measurement {
+            temperature: Some(temperature as f32),
+            pressure: Some(pressure as f32),
+            humidity: Some(humidity as f32),
+            air_quality: Some(iaq as f32),
+            bat_voltage: Some((4.4 + 1.6 * self.capacity / 100.0) as f32), // 4 AA cells
+            bat_capacity: Some(self.capacity as f32),
+            iaq: Some(iaq as f32),
+            iaq_accuracy: Some(iaq_accuracy),
+            co2: Some((400.0 + 5.0 * iaq) as f32),
+            voc: Some((0.5 + iaq / 50.0) as f32),
+            raw_gas: Some((250_000.0 / (1.0 + iaq / 50.0)) as f32),
+            stabilization_status: Some(uptime >= STABILIZATION_TIME),
+            run_in_status: Some(uptime >= RUN_IN_TIME),
+        }
+    }
+
+    fn device_info(&self, now: f64, rng: &mut impl Rng) -> DeviceInfo {
+        let mut model = [0; 16];
+        model[..4].copy_from_slice(b"SIM1");
+        let mut ssid = [0; 32];
+        ssid[..9].copy_from_slice(b"simulator");
+
+        DeviceInfo {
+            uptime: self.uptime(now) as u64,
+            report_interval: (self.config.sample_interval * self.config.report_divider as f64)
+                as u64,
+            sample_interval: self.config.sample_interval as u64,
+            firmware_version: [
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+                0,
+            ],
+            bsec_version: [2, 0, 0, 0],
+            model,
+            wifi_ssid: Some(ssid),
+            diagnostics: Some(Diagnostics {
+                rssi: Some(rng.gen_range(-85..=-45)),
+                connect_attempts: if rng.gen_bool(0.9) { 1 } else { 2 },
+                connect_duration: rng.gen_range(800..3000),
+                free_heap: rng.gen_range(140_000..160_000),
+                reset_reason: self.reset_reason,
+            }),
+        }
+    }
+
+    fn enqueue(&mut self, now: f64, payload: Payload) {
+        let mut header = Header::new(self.id, (self.uptime(now) * 1000.0) as u64);
+        header.boot_id = self.boot_id;
+        header.sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        self.queue.push(Packet { header, payload });
+    }
+
+    /// Encodes the queued packets, each one is lost with the configured drop rate
+    fn report(&mut self, now: f64, rng: &mut impl Rng) -> Result<Vec<Vec<u8>>> {
+        let sent_at = self.uptime(now) * 1000.0;
+        let mut datagrams = vec![];
+
+        for mut pkt in std::mem::take(&mut self.queue) {
+            // the backend dates packets by wall clock, scale the offset accordingly
+            pkt.header.rel_timestamp =
+                ((pkt.header.timestamp as f64 - sent_at) / self.time_scale) as i64;
+
+            let protection = match (&self.key, &mut self.nonce) {
+                (Some(key), Some(nonce)) => Protection::Encrypted(key, nonce.advance()),
+                (Some(key), None) => Protection::Authenticated(key),
+                _ => Protection::Plain,
+            };
+            let mut buf = [0; MAX_DATAGRAM_SIZE];
+            let buf = pkt.encode(&mut buf, protection)?;
+
+            if rng.gen_bool(self.config.drop_rate.clamp(0.0, 1.0)) {
+                log::debug!("Device {} dropped packet {}", self.id, pkt.header.sequence);
+                self.stats.dropped += 1;
+                continue;
+            }
+            datagrams.push(buf.to_vec());
+            self.stats.sent += 1;
+        }
+
+        Ok(datagrams)
+    }
+
+    /// Unsent samples are lost, like on the real device
+    fn reboot(&mut self, now: f64, reason: ResetReason) {
+        log::info!("Device {} reboots ({:?})", self.id, reason);
+        self.boot_id = self.boot_id.wrapping_add(1);
+        self.boot_time = now;
+        self.sequence = 0;
+        if self.nonce.is_some() {
+            self.nonce = Some(crypto::Nonce::new(self.boot_id));
+        }
+        self.reset_reason = reason;
+        self.queue.clear();
+        self.samples = 0;
+        self.stats.reboots += 1;
+    }
+}
+
+/// Evaluates the daily cycle at `hour` of the day
+fn sample(curve: &Curve, hour: f64, rng: &mut impl Rng) -> f64 {
+    let cycle = (2.0 * PI * (hour - curve.peak_hour) / 24.0).cos();
+    let noise = if curve.noise > 0.0 {
+        rng.gen_range(-curve.noise..=curve.noise)
+    } else {
+        0.0
+    };
+    curve.mean + curve.amplitude * cycle + noise
+}
+
+#[cfg(test)]
+mod tests {
+    use common::packet::Frame;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    /// Samples until the first report
+    fn report(group: &str) -> Vec<Vec<u8>> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut device =
+            VirtualDevice::new(7, toml::from_str(group).unwrap(), 1e9, 1.0, &mut rng).unwrap();
+        loop {
+            let datagrams = device.step(device.next_sample(), &mut rng).unwrap();
+            if !datagrams.is_empty() {
+                return datagrams;
+            }
+        }
+    }
+
+    fn check(packets: &[Packet]) {
+        assert!(matches!(
+            packets[0].payload,
+            Payload::Event(Event::Boot {
+                reset_reason: ResetReason::PowerOn
+            })
+        ));
+        assert!(matches!(packets[1].payload, Payload::Measurement(_)));
+        assert!(matches!(
+            packets.last().unwrap().payload,
+            Payload::DeviceInfo(_)
+        ));
+        for (sequence, pkt) in packets.iter().enumerate() {
+            assert_eq!(pkt.header.device_id, 7);
+            assert_eq!(pkt.header.sequence, sequence as u32);
+        }
+    }
+
+    #[test]
+    fn packets_decode() {
+        let packets = report("first_device_id = 7")
+            .iter()
+            .map(|buf| {
+                let frame = Frame::parse(buf).unwrap();
+                assert!(!frame.is_authenticated());
+                frame.packet().unwrap()
+            })
+            .collect::<Vec<_>>();
+        check(&packets);
+    }
+
+    #[test]
+    fn encrypted_packets_decode() {
+        let key = auth::key_from_hex(KEY).unwrap();
+        let packets = report(&format!(
+            "first_device_id = 7\nkey = \"{KEY}\"\nencrypt = true"
+        ))
+        .iter()
+        .map(|buf| {
+            let frame = Frame::parse(buf).unwrap();
+            assert!(frame.is_encrypted());
+            frame.decrypt(&key).unwrap()
+        })
+        .collect::<Vec<_>>();
+        check(&packets);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    net::UdpSocket,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use rand::{rngs::StdRng, SeedableRng};
+
+mod device;
+mod scenario;
+
+use device::{Stats, VirtualDevice};
+use scenario::Scenario;
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let path = std::env::args()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("scenario.toml"), PathBuf::from);
+    let scenario = Scenario::load(&path)?;
+
+    let mut rng = match scenario.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(&scenario.target)?;
+
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+    let mut devices = vec![];
+    for group in &scenario.groups {
+        for i in 0..group.count {
+            devices.push(VirtualDevice::new(
+                group.first_device_id + i,
+                group.clone(),
+                epoch,
+                scenario.time_scale,
+                &mut rng,
+            )?);
+        }
+    }
+    log::info!(
+        "Simulating {} devices at {}x, sending to {}",
+        devices.len(),
+        scenario.time_scale,
+        scenario.target
+    );
+
+    let start = Instant::now();
+    // the device sampling next
+    while let Some(device) = devices
+        .iter_mut()
+        .min_by(|a, b| a.next_sample().total_cmp(&b.next_sample()))
+    {
+        let now = device.next_sample();
+        if scenario.duration.is_some_and(|duration| now > duration) {
+            break;
+        }
+
+        // wait for the simulated time to pass
+        let at = Duration::from_secs_f64(now / scenario.time_scale);
+        std::thread::sleep(at.saturating_sub(start.elapsed()));
+
+        for datagram in device.step(now, &mut rng)? {
+            // e.g. connection refused while the backend is down
+            if let Err(err) = socket.send(&datagram) {
+                log::warn!("Cannot send packet of device {}: {}", device.id(), err);
+            }
+        }
+    }
+
+    let total = devices
+        .iter()
+        .fold(Stats::default(), |total, device| Stats {
+            sent: total.sent + device.stats.sent,
+            dropped: total.dropped + device.stats.dropped,
+            reboots: total.reboots + device.stats.reboots,
+        });
+    log::info!(
+        "Sent {} packets, dropped {}, {} reboots",
+        total.sent,
+        total.dropped,
+        total.reboots
+    );
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default = "default_target")]
+    pub target: String, // backend address
+    #[serde(default = "default_time_scale")]
+    pub time_scale: f64, // simulated seconds per second
+    pub duration: Option<f64>, // simulated seconds, runs forever if unset
+    pub seed: Option<u64>,     // reproducible runs
+    #[serde(rename = "group")]
+    pub groups: Vec<Group>,
+}
+
+/// Devices sharing the same configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    #[serde(default = "default_count")]
+    pub count: u32,
+    pub first_device_id: u32,
+    #[serde(default = "default_sample_interval")]
+    pub sample_interval: f64, // s
+    #[serde(default = "default_report_divider")]
+    pub report_divider: u32, // report every n-th sample
+    #[serde(default)]
+    pub jitter: f64, // s, random deviation of the sample interval
+    #[serde(default)]
+    pub drop_rate: f64, // probability of a lost packet
+    #[serde(default)]
+    pub reboot_rate: f64, // probability of a reboot per report
+    #[serde(default = "default_temperature")]
+    pub temperature: Curve, // °C
+    #[serde(default = "default_humidity")]
+    pub humidity: Curve, // percent
+    #[serde(default = "default_pressure")]
+    pub pressure: Curve, // Pa
+    #[serde(default = "default_iaq")]
+    pub iaq: Curve, // index
+    #[serde(default)]
+    pub battery: Battery,
+    pub key: Option<String>, // hex, packets are authenticated if set
+    #[serde(default)]
+    pub encrypt: bool, // requires `key`
+}
+
+/// Daily cycle peaking at `peak_hour`, with uniform noise on top
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Curve {
+    pub mean: f64,
+    #[serde(default)]
+    pub amplitude: f64,
+    #[serde(default)]
+    pub noise: f64,
+    #[serde(default = "default_peak_hour")]
+    pub peak_hour: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Battery {
+    #[serde(default = "default_capacity")]
+    pub capacity: f64, // percent at start
+    #[serde(default = "default_drain")]
+    pub drain: f64, // percent per day
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            drain: default_drain(),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read scenario {}", path.display()))?;
+        let scenario: Self = toml::from_str(&text)
+            .with_context(|| format!("invalid scenario {}", path.display()))?;
+
+        anyhow::ensure!(scenario.time_scale > 0.0, "time_scale must be positive");
+        for group in &scenario.groups {
+            anyhow::ensure!(
+                group.sample_interval > 0.0,
+                "sample_interval must be positive"
+            );
+            anyhow::ensure!(group.report_divider > 0, "report_divider must be positive");
+            anyhow::ensure!(
+                !group.encrypt || group.key.is_some(),
+                "encryption requires a key"
+            );
+        }
+
+        Ok(scenario)
+    }
+}
+
+fn default_target() -> String {
+    "127.0.0.1:8989".to_owned()
+}
+
+fn default_time_scale() -> f64 {
+    1.0
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+fn default_sample_interval() -> f64 {
+    300.0
+}
+
+fn default_report_divider() -> u32 {
+    3
+}
+
+fn default_peak_hour() -> f64 {
+    15.0
+}
+
+fn default_temperature() -> Curve {
+    Curve {
+        mean: 21.0,
+        amplitude: 2.0,
+        noise: 0.1,
+        peak_hour: default_peak_hour(),
+    }
+}
+
+fn default_humidity() -> Curve {
+    Curve {
+        mean: 45.0,
+        amplitude: -8.0, // lowest when it is warmest
+        noise: 0.5,
+        peak_hour: default_peak_hour(),
+    }
+}
+
+fn default_pressure() -> Curve {
+    Curve {
+        mean: 101325.0,
+        amplitude: 150.0,
+        noise: 10.0,
+        peak_hour: 10.0,
+    }
+}
+
+fn default_iaq() -> Curve {
+    Curve {
+        mean: 80.0,
+        amplitude: 40.0,
+        noise: 5.0,
+        peak_hour: 20.0,
+    }
+}
+
+fn default_capacity() -> f64 {
+    100.0
+}
+
+fn default_drain() -> f64 {
+    1.3 // about 2.5 months
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenario.toml");
+        let scenario = Scenario::load(&path).unwrap();
+        assert_eq!(scenario.groups.len(), 2);
+        assert_eq!(scenario.groups[0].count, 3);
+        assert_eq!(scenario.groups[1].battery.capacity, 5.0);
+    }
+
+    #[test]
+    fn encryption_requires_key() {
+        let path = std::env::temp_dir().join(format!("scenario-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[group]]\nfirst_device_id = 1\nencrypt = true\n").unwrap();
+        let err = Scenario::load(&path).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(err.to_string(), "encryption requires a key");
+    }
+}
@@ -0,0 +1,210 @@
+//! Captured datagrams, read from pcap files or raw dumps and recorded as raw dumps.
+//!
+//! A raw dump is a sequence of records: receive time (i64 LE, ms since the unix epoch),
+//! source address (u8 length + utf8), datagram length (u16 LE) and the datagram.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+const PCAP_MAGIC_US: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NS: u32 = 0xa1b23c4d;
+
+// link types
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IP_PROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub time: i64, // ms since the unix epoch
+    pub source: Option<SocketAddr>,
+    pub data: Vec<u8>,
+}
+
+/// Reads a pcap file (UDP datagrams sent to `port`) or a raw dump
+pub fn read(path: &Path, port: u16) -> Result<Vec<Datagram>> {
+    let mut buf = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .with_context(|| format!("cannot read {}", path.display()))?;
+
+    match buf
+        .get(..4)
+        .map(|m| u32::from_le_bytes(m.try_into().unwrap()))
+    {
+        Some(PCAP_MAGIC_US | PCAP_MAGIC_NS) => read_pcap(&buf, port, u32::from_le_bytes),
+        Some(magic) if matches!(magic.swap_bytes(), PCAP_MAGIC_US | PCAP_MAGIC_NS) => {
+            read_pcap(&buf, port, u32::from_be_bytes)
+        }
+        _ => read_dump(&buf),
+    }
+}
+
+fn read_dump(mut buf: &[u8]) -> Result<Vec<Datagram>> {
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if buf.len() < len {
+            bail!("truncated dump");
+        }
+        let (head, tail) = buf.split_at(len);
+        *buf = tail;
+        Ok(head)
+    }
+
+    let mut datagrams = vec![];
+    while !buf.is_empty() {
+        let time = i64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap());
+        let source_len = take(&mut buf, 1)?[0] as usize;
+        let source = std::str::from_utf8(take(&mut buf, source_len)?)?;
+        let len = u16::from_le_bytes(take(&mut buf, 2)?.try_into().unwrap()) as usize;
+        let data = take(&mut buf, len)?.to_vec();
+
+        datagrams.push(Datagram {
+            time,
+            source: source.parse().ok(),
+            data,
+        });
+    }
+
+    Ok(datagrams)
+}
+
+fn read_pcap(buf: &[u8], port: u16, u32_from: fn([u8; 4]) -> u32) -> Result<Vec<Datagram>> {
+    let field = |buf: &[u8], offset: usize| u32_from(buf[offset..offset + 4].try_into().unwrap());
+
+    if buf.len() < 24 {
+        bail!("truncated pcap header");
+    }
+    let nanos = field(buf, 0) == PCAP_MAGIC_NS;
+    let link_type = field(buf, 20) & 0x0fff_ffff;
+
+    let mut datagrams = vec![];
+    let mut rest = &buf[24..];
+    while rest.len() >= 16 {
+        let secs = field(rest, 0) as i64;
+        let frac = field(rest, 4) as i64;
+        let len = field(rest, 8) as usize;
+        let Some(frame) = rest.get(16..16 + len) else {
+            bail!("truncated pcap record");
+        };
+        rest = &rest[16 + len..];
+
+        let Some((source, dst_port, data)) = udp(link_type, frame)? else {
+            continue;
+        };
+        if dst_port != port {
+            continue;
+        }
+
+        let frac_ms = if nanos { frac / 1_000_000 } else { frac / 1000 };
+        datagrams.push(Datagram {
+            time: secs * 1000 + frac_ms,
+            source: Some(source),
+            data: data.to_vec(),
+        });
+    }
+
+    Ok(datagrams)
+}
+
+/// Unpacks the UDP payload of a captured frame, other traffic and IP fragments are skipped
+fn udp(link_type: u32, frame: &[u8]) -> Result<Option<(SocketAddr, u16, &[u8])>> {
+    let be16 = |b: &[u8], i: usize| b.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+    let (ethertype, ip) = match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            while be16(frame, offset) == Some(ETHERTYPE_VLAN) {
+                offset += 4;
+            }
+            (be16(frame, offset), frame.get(offset + 2..))
+        }
+        LINKTYPE_LINUX_SLL => (be16(frame, 14), frame.get(16..)),
+        LINKTYPE_LINUX_SLL2 => (be16(frame, 0), frame.get(20..)),
+        LINKTYPE_NULL => match frame.first() {
+            Some(2) => (Some(ETHERTYPE_IPV4), frame.get(4..)),
+            _ => (Some(ETHERTYPE_IPV6), frame.get(4..)),
+        },
+        LINKTYPE_RAW => match frame.first().map(|b| b >> 4) {
+            Some(4) => (Some(ETHERTYPE_IPV4), Some(frame)),
+            _ => (Some(ETHERTYPE_IPV6), Some(frame)),
+        },
+        _ => bail!("unsupported pcap link type {link_type}"),
+    };
+    let Some(ip) = ip else {
+        return Ok(None);
+    };
+
+    let (src, udp) = match ethertype {
+        Some(ETHERTYPE_IPV4) if ip.len() >= 20 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let fragmented = be16(ip, 6).is_some_and(|f| f & 0x3fff != 0);
+            if ip[9] != IP_PROTO_UDP || fragmented {
+                return Ok(None);
+            }
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            (IpAddr::V4(src), ip.get(header_len..))
+        }
+        Some(ETHERTYPE_IPV6) if ip.len() >= 40 => {
+            if ip[6] != IP_PROTO_UDP {
+                return Ok(None);
+            }
+            let src: [u8; 16] = ip[8..24].try_into().unwrap();
+            (IpAddr::V6(Ipv6Addr::from(src)), ip.get(40..))
+        }
+        _ => return Ok(None),
+    };
+
+    let Some(udp) = udp.filter(|udp| udp.len() >= 8) else {
+        return Ok(None);
+    };
+    let src_port = be16(udp, 0).unwrap();
+    let dst_port = be16(udp, 2).unwrap();
+    let len = (be16(udp, 4).unwrap() as usize).clamp(8, udp.len());
+
+    Ok(Some((
+        SocketAddr::new(src, src_port),
+        dst_port,
+        &udp[8..len],
+    )))
+}
+
+pub struct DumpWriter {
+    file: BufWriter<File>,
+}
+
+impl DumpWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, datagram: &Datagram) -> Result<()> {
+        let source = datagram.source.map(|s| s.to_string()).unwrap_or_default();
+        self.file.write_all(&datagram.time.to_le_bytes())?;
+        self.file.write_all(&[source.len() as u8])?;
+        self.file.write_all(source.as_bytes())?;
+        self.file
+            .write_all(&(datagram.data.len() as u16).to_le_bytes())?;
+        self.file.write_all(&datagram.data)?;
+
+        // keep the recording intact if the session is interrupted
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use chrono::{DateTime, Utc};
+use common::packet::{auth, DecodeError, Frame, Packet};
+use serde::Serialize;
+
+use crate::capture::Datagram;
+
+const MAX_AGE: i64 = 24 * 3600 * 1000; // ms, older packets are suspicious
+
+/// A decoded datagram with everything that looks off about it
+#[derive(Debug, Serialize)]
+pub struct Record {
+    pub time: DateTime<Utc>,
+    pub source: Option<SocketAddr>,
+    pub len: usize,
+    pub protection: Option<&'static str>,
+    pub packet: Option<Packet>,
+    pub error: Option<String>,
+    pub anomalies: Vec<String>,
+}
+
+/// Last packet seen of a device
+struct Last {
+    boot_id: u32,
+    sequence: u32,
+    timestamp: u64,
+}
+
+pub struct Decoder {
+    key: Option<auth::Key>,
+    last: HashMap<u32, Last>,
+}
+
+impl Decoder {
+    pub fn new(key: Option<auth::Key>) -> Self {
+        Self {
+            key,
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn decode(&mut self, datagram: &Datagram) -> Record {
+        let mut record = Record {
+            time: DateTime::from_timestamp_millis(datagram.time).unwrap_or_default(),
+            source: datagram.source,
+            len: datagram.data.len(),
+            protection: None,
+            packet: None,
+            error: None,
+            anomalies: vec![],
+        };
+
+        let frame = match Frame::parse(&datagram.data) {
+            Ok(frame) => frame,
+            Err(err) => {
+                record.error = Some(err.to_string());
+                return record;
+            }
+        };
+
+        record.protection = Some(if frame.is_encrypted() {
+            "encrypted"
+        } else if frame.is_authenticated() {
+            "authenticated"
+        } else {
+            "plain"
+        });
+
+        let packet = match (&self.key, frame.is_encrypted()) {
+            (Some(key), true) => frame.decrypt(key),
+            (None, true) => Err(DecodeError::Encrypted),
+            (Some(key), false) => {
+                if frame.is_authenticated() && !frame.verify(key) {
+                    record.anomalies.push("authentication failed".to_owned());
+                }
+                frame.packet()
+            }
+            (None, false) => frame.packet(),
+        };
+
+        match packet {
+            Ok(packet) => {
+                self.check(&packet, &mut record.anomalies);
+                record.packet = Some(packet);
+            }
+            Err(err) => record.error = Some(err.to_string()),
+        }
+
+        record
+    }
+
+    fn check(&mut self, packet: &Packet, anomalies: &mut Vec<String>) {
+        let header = &packet.header;
+
+        if header.rel_timestamp > 0 {
+            anomalies.push(format!(
+                "dated {} ms after it was sent",
+                header.rel_timestamp
+            ));
+        }
+        if header.rel_timestamp < -MAX_AGE {
+            anomalies.push(format!(
+                "sent {} h after it was dated",
+                -header.rel_timestamp / 3_600_000
+            ));
+        }
+
+        if !header.has_sequence() {
+            return;
+        }
+
+        let last = self.last.insert(
+            header.device_id,
+            Last {
+                boot_id: header.boot_id,
+                sequence: header.sequence,
+                timestamp: header.timestamp,
+            },
+        );
+        let Some(last) = last.filter(|last| last.boot_id == header.boot_id) else {
+            return;
+        };
+
+        if header.sequence <= last.sequence {
+            anomalies.push(format!(
+                "sequence {} not after {}, duplicate or reordered",
+                header.sequence, last.sequence
+            ));
+        } else if header.sequence - last.sequence > 1 {
+            anomalies.push(format!(
+                "{} packets missing before sequence {}",
+                header.sequence - last.sequence - 1,
+                header.sequence
+            ));
+        }
+        if header.sequence > last.sequence && header.timestamp < last.timestamp {
+            anomalies.push(format!(
+                "timestamp went back by {} ms within the boot",
+                last.timestamp - header.timestamp
+            ));
+        }
+    }
+}
+
+/// Prints the record as a single JSON line or as indented text
+pub fn print(record: &Record, json: bool) {
+    if json {
+        match serde_json::to_string(record) {
+            Ok(line) => println!("{line}"),
+            Err(err) => eprintln!("Cannot serialize record: {err}"),
+        }
+        return;
+    }
+
+    let source = record
+        .source
+        .map_or_else(|| "-".to_owned(), |source| source.to_string());
+    let mut line = format!(
+        "{} {} {} bytes",
+        record.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+        source,
+        record.len
+    );
+    if let Some(protection) = record.protection {
+        line += &format!(" {protection}");
+    }
+    if let Some(packet) = &record.packet {
+        let header = &packet.header;
+        line += &format!(
+            " v{} device {} boot {} seq {} ts {} ms rel {} ms",
+            header.version(),
+            header.device_id,
+            header.boot_id,
+            header.sequence,
+            header.timestamp,
+            header.rel_timestamp
+        );
+    }
+    println!("{line}");
+
+    if let Some(packet) = &record.packet {
+        for line in format!("{:#?}", packet.payload).lines() {
+            println!("    {line}");
+        }
+    }
+    if let Some(err) = &record.error {
+        println!("  x {err}");
+    }
+    for anomaly in &record.anomalies {
+        println!("  ! {anomaly}");
+    }
+}
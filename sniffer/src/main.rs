@@ -0,0 +1,147 @@
+use std::{
+    net::UdpSocket,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use common::packet::auth;
+
+mod capture;
+mod decode;
+
+use capture::{Datagram, DumpWriter};
+use decode::Decoder;
+
+/// Decodes, records and replays smart meter packets
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decodes the packets broadcast by the sensors
+    Listen {
+        #[arg(long, default_value_t = 8989)]
+        port: u16,
+        /// Records the session as raw dump
+        #[arg(long)]
+        record: Option<PathBuf>,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Decodes the packets of a pcap file or raw dump
+    Read {
+        file: PathBuf,
+        /// UDP destination port of the packets in a pcap file
+        #[arg(long, default_value_t = 8989)]
+        port: u16,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Sends the packets of a pcap file or raw dump to the backend, keeping their timing
+    Replay {
+        file: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:8989")]
+        target: String,
+        /// UDP destination port of the packets in a pcap file
+        #[arg(long, default_value_t = 8989)]
+        port: u16,
+        /// Playback speed, 0 sends all packets at once
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+}
+
+#[derive(Args)]
+struct Output {
+    /// Prints one JSON object per packet
+    #[arg(long)]
+    json: bool,
+    /// Device key (hex) to verify and decrypt packets
+    #[arg(long)]
+    key: Option<String>,
+}
+
+impl Output {
+    fn decoder(&self) -> Result<Decoder> {
+        let key = match &self.key {
+            Some(key) => Some(auth::key_from_hex(key).ok_or_else(|| anyhow!("invalid key"))?),
+            None => None,
+        };
+        Ok(Decoder::new(key))
+    }
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Listen {
+            port,
+            record,
+            output,
+        } => listen(port, record, output),
+        Command::Read { file, port, output } => {
+            let mut decoder = output.decoder()?;
+            for datagram in capture::read(&file, port)? {
+                decode::print(&decoder.decode(&datagram), output.json);
+            }
+            Ok(())
+        }
+        Command::Replay {
+            file,
+            target,
+            port,
+            speed,
+        } => replay(capture::read(&file, port)?, &target, speed),
+    }
+}
+
+fn listen(port: u16, record: Option<PathBuf>, output: Output) -> Result<()> {
+    let mut decoder = output.decoder()?;
+    let mut writer = record.as_deref().map(DumpWriter::create).transpose()?;
+
+    // fails while the backend is bound to the same port on this host
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    eprintln!("Listening on port {port}...");
+
+    let mut buf = [0; 2048];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf)?;
+        let datagram = Datagram {
+            time: chrono::Utc::now().timestamp_millis(),
+            source: Some(source),
+            data: buf[..len].to_vec(),
+        };
+
+        if let Some(writer) = &mut writer {
+            writer.write(&datagram)?;
+        }
+        decode::print(&decoder.decode(&datagram), output.json);
+    }
+}
+
+fn replay(datagrams: Vec<Datagram>, target: &str, speed: f64) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(target)?;
+
+    let start = Instant::now();
+    let first = datagrams.first().map_or(0, |datagram| datagram.time);
+    let mut sent = 0;
+    for datagram in &datagrams {
+        if speed > 0.0 {
+            let at = Duration::from_millis((datagram.time - first).max(0) as u64).div_f64(speed);
+            std::thread::sleep(at.saturating_sub(start.elapsed()));
+        }
+        // e.g. connection refused while the backend is down
+        match socket.send(&datagram.data) {
+            Ok(_) => sent += 1,
+            Err(err) => eprintln!("Cannot send packet: {err}"),
+        }
+    }
+    eprintln!("Sent {} of {} packets to {}", sent, datagrams.len(), target);
+
+    Ok(())
+}